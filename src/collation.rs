@@ -0,0 +1,173 @@
+/*
+
+# Alphabetical order
+
+The players list the groups and the titles in the order of GPLB and TPLB, so the alphabetical order of the views is
+the one used when writing them, not the byte order of the texts.
+Two texts are compared by their collation key:
+- a leading article of an artist, like "The" in "The Beatles", is ignored (but not the one of a title: SonicStage
+  lists "The way you love me" before "Waste"),
+- the accented letters are compared as their base letter ("Björk" as "Bjork", "Æ" as "AE"),
+- the katakana are compared as the corresponding hiragana,
+- the case is ignored ("bjork" is before "Zappa").
+The texts with the same key are then in byte order, so that the order is always the same.
+Each rule can be disabled in `Collation`.
+
+The TSOP frame (sort-as) gives the text to sort an artist by, like "Beatles, The": when present, it is compared
+instead of the name.
+
+*/
+
+use std::cmp::Ordering;
+
+/// The letters compared as a base letter.
+const ACCENTS: &[(&str, &str)] = &[
+    ("ÀÁÂÃÄÅĀĂĄ", "A"), ("àáâãäåāăą", "a"), ("ÇĆĈĊČ", "C"), ("çćĉċč", "c"), ("ĎĐ", "D"), ("ďđ", "d"),
+    ("ÈÉÊËĒĔĖĘĚ", "E"), ("èéêëēĕėęě", "e"), ("ĜĞĠĢ", "G"), ("ĝğġģ", "g"), ("ĤĦ", "H"), ("ĥħ", "h"),
+    ("ÌÍÎÏĨĪĬĮİ", "I"), ("ìíîïĩīĭįı", "i"), ("Ĵ", "J"), ("ĵ", "j"), ("Ķ", "K"), ("ķ", "k"), ("ĹĻĽĿŁ", "L"),
+    ("ĺļľŀł", "l"), ("ÑŃŅŇ", "N"), ("ñńņň", "n"), ("ÒÓÔÕÖØŌŎŐ", "O"), ("òóôõöøōŏő", "o"), ("ŔŖŘ", "R"),
+    ("ŕŗř", "r"), ("ŚŜŞŠ", "S"), ("śŝşš", "s"), ("ŢŤŦ", "T"), ("ţťŧ", "t"), ("ÙÚÛÜŨŪŬŮŰŲ", "U"),
+    ("ùúûüũūŭůűų", "u"), ("Ŵ", "W"), ("ŵ", "w"), ("ÝŶŸ", "Y"), ("ýÿŷ", "y"), ("ŹŻŽ", "Z"), ("źżž", "z"),
+    ("Æ", "AE"), ("æ", "ae"), ("Œ", "OE"), ("œ", "oe"), ("ß", "ss"), ("Þ", "TH"), ("þ", "th"),
+];
+
+/// The rules of the alphabetical order.
+#[derive(Clone)]
+pub struct Collation {
+    /// The leading words of artist names which are ignored, like "The".
+    pub articles: Vec<String>,
+    /// Compare the accented letters as their base letter.
+    pub ignore_accents: bool,
+    /// Compare the uppercase letters as the lowercase ones.
+    pub ignore_case: bool,
+    /// Compare the katakana as the hiragana.
+    pub ignore_kana: bool,
+}
+
+impl Default for Collation {
+    fn default() -> Self {
+        Collation {
+            articles: vec!["The".to_string()],
+            ignore_accents: true,
+            ignore_case: true,
+            ignore_kana: true,
+        }
+    }
+}
+
+impl Collation {
+    /// The order of the texts.
+    pub fn compare(&self, string1: &str, string2: &str) -> Ordering {
+        self.key(string1).cmp(&self.key(string2))
+            .then_with(|| string1.cmp(string2))
+    }
+
+    /// The order of the artist names, sorted by their sort name (the TSOP frame) when it is not empty.
+    pub fn compare_names(&self, name1: &str, sort_name1: &str, name2: &str, sort_name2: &str) -> Ordering {
+        let key1 = self.key(self.strip_article(sort_text(name1, sort_name1)));
+        let key2 = self.key(self.strip_article(sort_text(name2, sort_name2)));
+        key1.cmp(&key2)
+            .then_with(|| self.compare(name1, name2))
+    }
+
+    /// The text which is compared instead of `string`.
+    pub fn key(&self, string: &str) -> String {
+        let mut key = String::with_capacity(string.len());
+        for character in string.chars() {
+            match base_letters(character).filter(|_| self.ignore_accents) {
+                Some(letters) => key.push_str(letters),
+                None if self.ignore_kana && is_katakana(character) =>
+                    key.push(hiragana(character)),
+                None => key.push(character),
+            }
+        }
+        if self.ignore_case {
+            key.to_lowercase()
+        }
+        else {
+            key
+        }
+    }
+
+    fn strip_article<'a>(&self, string: &'a str) -> &'a str {
+        for article in &self.articles {
+            let length = article.len();
+            let is_article = string.len() > length + 1 && string.is_char_boundary(length) &&
+                string[..length].eq_ignore_ascii_case(article) && string[length..].starts_with(' ');
+            if is_article {
+                return string[length..].trim_start();
+            }
+        }
+        string
+    }
+}
+
+fn base_letters(character: char) -> Option<&'static str> {
+    ACCENTS.iter()
+        .find(|&&(letters, _)| letters.contains(character))
+        .map(|&(_, base)| base)
+}
+
+fn hiragana(character: char) -> char {
+    ::std::char::from_u32(character as u32 - 0x60).unwrap_or(character)
+}
+
+/// The katakana which have a hiragana, from ァ to ヶ.
+fn is_katakana(character: char) -> bool {
+    ('\u{30A1}'..='\u{30F6}').contains(&character)
+}
+
+fn sort_text<'a>(name: &'a str, sort_name: &'a str) -> &'a str {
+    if sort_name.is_empty() {
+        name
+    }
+    else {
+        sort_name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::Collation;
+
+    #[test]
+    fn compare_names() {
+        let collation = Collation::default();
+        assert_eq!(collation.compare_names("bjork", "", "Zappa", ""), Ordering::Less);
+        assert_eq!(collation.compare_names("Björk", "", "Zappa", ""), Ordering::Less);
+        assert_eq!(collation.compare_names("Björk", "", "Bjork", ""), Ordering::Greater);
+        assert_eq!(collation.compare_names("The Beatles", "", "Blur", ""), Ordering::Less);
+        assert_eq!(collation.compare_names("The Beatles", "", "Archive", ""), Ordering::Greater);
+        assert_eq!(collation.compare_names("Zappa", "Beatles, The", "Blur", ""), Ordering::Less);
+        // The articles of the titles are kept.
+        assert_eq!(collation.compare("The way you love me", "Waste"), Ordering::Less);
+
+        let collation = Collation {
+            articles: vec![],
+            ignore_accents: false,
+            ignore_case: false,
+            ignore_kana: false,
+        };
+        assert_eq!(collation.compare_names("bjork", "", "Zappa", ""), Ordering::Greater);
+        assert_eq!(collation.compare_names("Björk", "", "Bjorn", ""), Ordering::Greater);
+        assert_eq!(collation.compare_names("The Beatles", "", "Blur", ""), Ordering::Greater);
+    }
+
+    #[test]
+    fn key() {
+        let collation = Collation::default();
+        assert_eq!(collation.key("Björk"), "bjork");
+        assert_eq!(collation.key("Ærøskøbing"), "aeroskobing");
+        assert_eq!(collation.key("カタカナ"), "かたかな");
+        assert_eq!(collation.key("ヴァ"), collation.key("ゔぁ"));
+        assert_eq!(collation.key("漢字"), "漢字");
+
+        let collation = Collation {
+            ignore_kana: false,
+            .. Collation::default()
+        };
+        assert_eq!(collation.key("カタカナ"), "カタカナ");
+    }
+}
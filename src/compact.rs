@@ -0,0 +1,257 @@
+/*
+
+# Compacting 04CNTINF
+
+When a title is deleted, its element stays in 04CNTINF and 05CIDLST: its title_id is free, but the element is still
+there as a ghost. Compacting finds the free title_ids (the elements without file in 10F0X), removes them from the TPLB
+classes of 01TREEXX and then either:
+- blanks their elements, so that they are clearly free (the free title_ids at the end of the list are removed),
+- or renumbers the titles, so that there is no free title_id anymore: the files in 10F0X are renamed, and every
+  reference to a title_id (in 05CIDLST and in the TPLB classes of 01TREEXX) is updated.
+The items of 03GINFXX and the order of the views are kept: a group which loses all its titles goes to the unused tail
+of GPLB, like when deleting titles, and the keys are recomputed.
+
+The files are renamed when calling `save`, after the tables are written in memory and before they are written in their
+files: when a rename fails, the files renamed before are renamed back and the tables are not changed.
+
+*/
+
+use std::mem;
+
+use database::OmaDatabase;
+use parser::{Association, Frame, Result, Table, TitleId};
+use repair::update_keys;
+use tree::groups;
+
+/// What happens to the free title_ids.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Compaction {
+    /// Blank the elements of the free title_ids.
+    Free,
+    /// Give the titles consecutive title_ids, from 1.
+    Renumber,
+}
+
+impl OmaDatabase {
+    /// Compact 04CNTINF and return the free title_ids which were found.
+    /// The files are renamed in 10F0X with the tables, when calling `save`.
+    pub fn compact(&mut self, compaction: Compaction) -> Result<Vec<TitleId>> {
+        let count = self.cntinf.cnfb()?.len() as TitleId;
+        let free = self.storage.free_titles(count as usize);
+        let mut new_ids = vec![0; count as usize + 1];
+        let mut next_id = 1;
+        for title_id in 1..=count {
+            if !free.contains(&title_id) {
+                new_ids[title_id as usize] =
+                    match compaction {
+                        Compaction::Free => title_id,
+                        Compaction::Renumber => next_id,
+                    };
+                next_id += 1;
+            }
+        }
+
+        match compaction {
+            Compaction::Free => self.blank_titles(&free)?,
+            Compaction::Renumber => self.renumber_titles(&free, &new_ids)?,
+        }
+        let artist_view = self.view_keyed_on(b"TPE1");
+        for (&view, tree) in &mut self.trees {
+            renumber_tree(tree, &new_ids, artist_view == Some(view))?;
+        }
+        self.cntinf.relayout();
+        self.cidlst.relayout();
+        self.link()?;
+        let views: Vec<_> = self.trees.keys().cloned().collect();
+        update_keys(self, &views)?;
+        Ok(free)
+    }
+
+    /// Blank the elements of the titles, and remove them when they are at the end of the list.
+    fn blank_titles(&mut self, title_ids: &[TitleId]) -> Result<()> {
+        for &title_id in title_ids {
+            let index = title_id as usize - 1;
+            let element = &mut self.cntinf.cnfb_mut()?[index];
+            element.title_key = 0;
+            for frame in &mut element.frames {
+                *frame = Frame::new(&frame.tag, "", element.part_size);
+            }
+            if let Some(element) = self.cidlst.cilb_mut()?.get_mut(index) {
+                element.drmed_oma_key = vec![0; 0x18];
+            }
+        }
+        let mut count = self.cntinf.cnfb()?.len();
+        while count > 0 && title_ids.contains(&(count as TitleId)) {
+            count -= 1;
+        }
+        self.cntinf.cnfb_mut()?.truncate(count);
+        self.cidlst.cilb_mut()?.truncate(count);
+        Ok(())
+    }
+
+    /// Remove the elements of the titles and give the other titles their new title_ids, renaming their files.
+    fn renumber_titles(&mut self, title_ids: &[TitleId], new_ids: &[TitleId]) -> Result<()> {
+        // The titles are moved to lower title_ids, in increasing order, so that a file is never overwritten.
+        for (title_id, &new_id) in new_ids.iter().enumerate() {
+            let title_id = title_id as TitleId;
+            if new_id != 0 && new_id != title_id {
+                self.storage.rename_title(title_id, new_id)?;
+            }
+        }
+
+        let mut title_id = 0;
+        self.cntinf.cnfb_mut()?.retain(|_| {
+            title_id += 1;
+            !title_ids.contains(&title_id)
+        });
+        let mut title_id = 0;
+        self.cidlst.cilb_mut()?.retain(|_| {
+            title_id += 1;
+            !title_ids.contains(&title_id)
+        });
+        for (index, element) in self.cidlst.cilb_mut()?.iter_mut().enumerate() {
+            element.title_id = index as TitleId + 1;
+        }
+        Ok(())
+    }
+}
+
+/// Give the titles of TPLB their new title_ids, removing the titles whose new title_id is 0.
+/// The groups keep their place in GPLB and start at their first remaining title. The groups which lose all their
+/// titles, and the artists of 01TREE2D which lose all their albums, go to the unused tail of GPLB.
+/// `count_used_groups` tells whether the class header of GPLB repeats the number of used groups (see repair).
+fn renumber_tree(tree: &mut Table, new_ids: &[TitleId], count_used_groups: bool) -> Result<()> {
+    let new_id = |title_id: TitleId| new_ids.get(title_id as usize).cloned().unwrap_or(0);
+    let title_ids: Vec<_> = tree.tplb()?.iter()
+        .map(|element| element.title_id)
+        .collect();
+    // The number of remaining titles before each title of TPLB.
+    let mut kept_before = vec![0];
+    for &title_id in &title_ids {
+        let kept = kept_before[kept_before.len() - 1] + (new_id(title_id) != 0) as u16;
+        kept_before.push(kept);
+    }
+
+    let emptied: Vec<_> = groups(tree)?.iter()
+        .map(|group| group.used && !group.titles.is_empty() && group.titles.iter().all(|&title_id| new_id(title_id) == 0))
+        .collect();
+    let mut gplb = mem::take(tree.gplb_mut()?);
+    let mut unused = vec![];
+    for index in 0..gplb.len() {
+        let element = &gplb[index];
+        let albums: Vec<_> = (index + 1..gplb.len())
+            .take_while(|&album| gplb[album].association == Association::Album)
+            .collect();
+        let is_empty_artist = element.association == Association::Used && !albums.is_empty() &&
+            albums.iter().all(|&album| emptied[album]);
+        if emptied[index] || is_empty_artist {
+            unused.push(index);
+        }
+    }
+    for element in &mut gplb {
+        if element.association.is_used() {
+            let first = (element.title_id as usize).clamp(1, kept_before.len()) - 1;
+            element.title_id = kept_before[first] + 1;
+        }
+    }
+    for &index in &unused {
+        gplb[index].association = Association::Unused;
+        gplb[index].title_id = 0;
+    }
+    let (mut used, mut unused): (Vec<_>, Vec<_>) = gplb.into_iter()
+        .partition(|element| element.association.is_used());
+    let used_count = used.len();
+    used.append(&mut unused);
+    let group_count = if count_used_groups { used_count } else { used.len() };
+    *tree.gplb_mut()? = used;
+
+    let title_count = {
+        let elements = tree.tplb_mut()?;
+        for element in elements.iter_mut() {
+            element.title_id = new_id(element.title_id);
+        }
+        elements.retain(|element| element.title_id != 0);
+        elements.len()
+    };
+    tree.class_mut(b"GPLB")?.set_repeated_count(group_count as u16);
+    tree.class_mut(b"TPLB")?.set_repeated_count(title_count as u16);
+    tree.relayout();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use database::OmaDatabase;
+    use database::tests::{FIXTURE_TRACKS, fixtures_copy};
+    use parser::TitleId;
+    use verify::verify;
+    use super::Compaction;
+
+    /// A copy of the fixtures where the titles 3 (Knives out), 8 and 10 (Waste) have no file.
+    fn fixtures_with_gaps(name: &str) -> PathBuf {
+        let tracks: Vec<_> = FIXTURE_TRACKS.iter()
+            .cloned()
+            .filter(|&(title_id, _)| title_id != 3 && title_id != 10)
+            .collect();
+        fixtures_copy(name, &tracks)
+    }
+
+    fn tplb(database: &OmaDatabase, view: u8) -> Vec<TitleId> {
+        database.trees[&view].tplb().unwrap().iter().map(|element| element.title_id).collect()
+    }
+
+    fn gplb_starts(database: &OmaDatabase, view: u8) -> Vec<u16> {
+        database.trees[&view].gplb().unwrap().iter()
+            .filter(|element| element.association.is_used())
+            .map(|element| element.title_id)
+            .collect()
+    }
+
+    #[test]
+    fn free_titles() {
+        let path = fixtures_with_gaps("compact-free");
+        let mut database = OmaDatabase::open(&path).unwrap();
+        assert_eq!(database.compact(Compaction::Free).unwrap(), vec![3, 8, 10]);
+        database.save().unwrap();
+
+        let database = OmaDatabase::open(&path).unwrap();
+        assert!(verify(&database).unwrap().is_empty());
+        // The free title_id 10 at the end of the list is removed, 3 and 8 are blanked.
+        assert_eq!(database.cntinf.cnfb().unwrap().len(), 9);
+        assert_eq!(database.cidlst.cilb().unwrap().len(), 9);
+        let title = database.title(3).unwrap();
+        assert_eq!((title.name.as_str(), title.key), ("", 0));
+        assert_eq!(tplb(&database, 1), vec![1, 2, 4, 5, 6, 7, 9]);
+        assert_eq!(gplb_starts(&database, 1), vec![1, 4, 7]);
+        assert!(path.join("10F00/10000009.OMA").exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn renumber_titles() {
+        let path = fixtures_with_gaps("compact-renumber");
+        let mut database = OmaDatabase::open(&path).unwrap();
+        assert_eq!(database.compact(Compaction::Renumber).unwrap(), vec![3, 8, 10]);
+        database.save().unwrap();
+
+        let database = OmaDatabase::open(&path).unwrap();
+        assert!(verify(&database).unwrap().is_empty());
+        assert_eq!(database.cntinf.cnfb().unwrap().len(), 7);
+        assert_eq!(database.cidlst.cilb().unwrap().len(), 7);
+        let names: Vec<_> = database.titles().iter().map(|title| title.name.as_str()).collect();
+        assert_eq!(names, vec!["Pyramid song", "I migth be wrong", "Hunting Bears", "You make me feel",
+            "The way you love me", "Cloud in the sky", "Fuck U"]);
+        assert_eq!(tplb(&database, 1), vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(gplb_starts(&database, 1), vec![1, 4, 7]);
+        // The files of the titles 4 to 9 are renamed, the last ones are gone.
+        for title_id in 1..=7 {
+            assert!(path.join(format!("10F00/1000000{}.OMA", title_id)).exists());
+        }
+        assert!(!path.join("10F00/10000008.OMA").exists());
+        assert!(!path.join("10F00/10000009.OMA").exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
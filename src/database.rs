@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use collation::Collation;
+use parser::{CnfbElement, GpfbElement, Result, Table, TitleId, parse_table};
+use storage::{Storage, find_file, read_file};
+use tree::groups;
+use writer::write_table;
+
+/// The view of the titles in upload order, 01TREE01 and 03GINF01, whose items are albums.
+pub const UPLOADED_VIEW: u8 = 1;
+
+/// The content of an OMGAUDIO folder, with every title linked to its groups in the TREE views.
+pub struct OmaDatabase {
+    pub path: PathBuf,
+    /// 00GTRLST: the list of views.
+    pub gtrlst: Table,
+    /// 01TREEXX: the structure of each view, indexed by XX.
+    pub trees: BTreeMap<u8, Table>,
+    /// 02TREINF: the description of each view.
+    pub treinf: Table,
+    /// 03GINFXX: the groups (albums, artists, genres) of each view, indexed by XX.
+    pub ginfs: BTreeMap<u8, Table>,
+    /// 04CNTINF: the list of titles.
+    pub cntinf: Table,
+    /// 05CIDLST: the content id of each title.
+    pub cidlst: Table,
+    /// The alphabetical order used when sorting the views.
+    pub collation: Collation,
+    /// The files of 10F0X, written when calling `save`.
+    pub storage: Storage,
+    titles: Vec<Title>,
+}
+
+pub struct Title {
+    pub id: TitleId,
+    pub key: u32,
+    pub name: String,
+    pub artist: String,
+    /// The text to sort the artist by (the TSOP frame), or an empty string.
+    pub sort_name: String,
+    pub album: String,
+    pub genre: String,
+    pub positions: Vec<TreePosition>,
+}
+
+impl Title {
+    pub fn position(&self, view: u8) -> Option<&TreePosition> {
+        self.positions.iter().find(|position| position.view == view)
+    }
+}
+
+/// The place of a title in a view.
+pub struct TreePosition {
+    pub view: u8,
+    /// The index of the group in GPLB.
+    pub group: usize,
+    /// The item_id of the group in 03GINFXX.
+    pub item_id: u16,
+    /// The title_id_in_TPLBlist of the title.
+    pub index: u16,
+}
+
+impl OmaDatabase {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let gtrlst = read_table(&path, "00GTRLST.DAT")?;
+        let mut trees = BTreeMap::new();
+        let mut ginfs = BTreeMap::new();
+        for view in views(&gtrlst)? {
+            let tree = find_file(&path, &format!("01TREE{:02X}.DAT", view));
+            let ginf = find_file(&path, &format!("03GINF{:02X}.DAT", view));
+            if let (Some(tree), Some(ginf)) = (tree, ginf) {
+                trees.insert(view, read_table_file(&tree)?);
+                ginfs.insert(view, read_table_file(&ginf)?);
+            }
+        }
+        let treinf = read_table(&path, "02TREINF.DAT")?;
+        let cntinf = read_table(&path, "04CNTINF.DAT")?;
+        let cidlst = read_table(&path, "05CIDLST.DAT")?;
+        let storage = Storage::open(&path);
+        let mut database = OmaDatabase {
+            path,
+            gtrlst,
+            trees,
+            treinf,
+            ginfs,
+            cntinf,
+            cidlst,
+            collation: Collation::default(),
+            storage,
+            titles: vec![],
+        };
+        database.link()?;
+        Ok(database)
+    }
+
+    pub fn album(&self, title: &Title) -> Option<&GpfbElement> {
+        self.group_info_by_tag(title, b"TALB")
+    }
+
+    pub fn artist(&self, title: &Title) -> Option<&GpfbElement> {
+        self.group_info_by_tag(title, b"TPE1")
+    }
+
+    pub fn genre(&self, title: &Title) -> Option<&GpfbElement> {
+        self.group_info_by_tag(title, b"TCON")
+    }
+
+    /// The element of 03GINFXX describing the group of the title in the view XX.
+    pub fn group_info(&self, title: &Title, view: u8) -> Option<&GpfbElement> {
+        let position = title.position(view)?;
+        let elements = self.ginfs.get(&view)?.gpfb().ok()?;
+        elements.get((position.item_id as usize).checked_sub(1)?)
+    }
+
+    /// Write every table back to its file, with the changes of the files of 10F0X (see `Storage::save`).
+    pub fn save(&mut self) -> Result<()> {
+        let mut tables = vec![table_file(&self.path, "00GTRLST.DAT", &self.gtrlst)?];
+        for (&view, tree) in &self.trees {
+            tables.push(table_file(&self.path, &format!("01TREE{:02X}.DAT", view), tree)?);
+        }
+        tables.push(table_file(&self.path, "02TREINF.DAT", &self.treinf)?);
+        for (&view, ginf) in &self.ginfs {
+            tables.push(table_file(&self.path, &format!("03GINF{:02X}.DAT", view), ginf)?);
+        }
+        tables.push(table_file(&self.path, "04CNTINF.DAT", &self.cntinf)?);
+        tables.push(table_file(&self.path, "05CIDLST.DAT", &self.cidlst)?);
+        self.storage.save(&tables)
+    }
+
+    pub fn title(&self, id: TitleId) -> Option<&Title> {
+        self.titles.get((id as usize).checked_sub(1)?)
+    }
+
+    pub fn titles(&self) -> &[Title] {
+        &self.titles
+    }
+
+    /// The view keyed only on the ID3 tag, like 02 for TPE1.
+    pub fn view_keyed_on(&self, tag: &[u8]) -> Option<u8> {
+        self.gtrlst.gtlb().ok()?.iter()
+            .find(|element| element.tags.len() == 1 && element.tags[0] == tag && self.trees.contains_key(&(element.file_ref as u8)))
+            .map(|element| element.file_ref as u8)
+    }
+
+    fn group_info_by_tag(&self, title: &Title, tag: &[u8]) -> Option<&GpfbElement> {
+        self.group_info(title, self.view_keyed_on(tag)?)
+    }
+
+    /// Rebuild the titles from the tables, after they changed.
+    pub fn link(&mut self) -> Result<()> {
+        let mut titles = vec![];
+        for (index, element) in self.cntinf.cnfb()?.iter().enumerate() {
+            titles.push(title(index as TitleId + 1, element)?);
+        }
+        for (&view, tree) in &self.trees {
+            for (index, group) in groups(tree)?.iter().enumerate() {
+                for (offset, &title_id) in group.titles.iter().enumerate() {
+                    let title = (title_id as usize).checked_sub(1)
+                        .and_then(|title| titles.get_mut(title));
+                    if let Some(title) = title {
+                        title.positions.push(TreePosition {
+                            view,
+                            group: index,
+                            item_id: group.ginf_item,
+                            index: group.first + offset as u16,
+                        });
+                    }
+                }
+            }
+        }
+        self.titles = titles;
+        Ok(())
+    }
+}
+
+fn read_table(path: &Path, name: &str) -> Result<Table> {
+    let file = find_file(path, name)
+        .ok_or_else(|| format!("Cannot find {} in {}", name, path.display()))?;
+    read_table_file(&file)
+}
+
+fn read_table_file(path: &Path) -> Result<Table> {
+    parse_table(&read_file(path)?)
+        .map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn title(id: TitleId, element: &CnfbElement) -> Result<Title> {
+    Ok(Title {
+        id,
+        key: element.title_key,
+        name: element.text(b"TIT2")?,
+        artist: element.text(b"TPE1")?,
+        sort_name: element.sort_name()?,
+        album: element.text(b"TALB")?,
+        genre: element.text(b"TCON")?,
+        positions: vec![],
+    })
+}
+
+/// The views listed in 00GTRLST.
+fn views(gtrlst: &Table) -> Result<Vec<u8>> {
+    Ok(gtrlst.gtlb()?.iter()
+        .map(|element| element.file_ref as u8)
+        .collect())
+}
+
+/// The path and the content of the file of the table.
+fn table_file(path: &Path, name: &str, table: &Table) -> Result<(PathBuf, Vec<u8>)> {
+    let path = find_file(path, name)
+        .unwrap_or_else(|| path.join(name));
+    let content = write_table(table)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok((path, content))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use parser::TitleId;
+    use parser::music::Tag;
+    use parser::music::tests::mpeg_frames;
+    use storage::{read_file, write_file};
+    use writer::music::mp3_to_oma;
+    use super::OmaDatabase;
+
+    /// The track numbers of the titles of the fixtures in their albums. The title 8 has no file: its title_id is free.
+    pub const FIXTURE_TRACKS: &[(TitleId, u32)] = &[(1, 1), (2, 2), (3, 3), (4, 4), (5, 1), (6, 2), (7, 3), (9, 1), (10, 2)];
+
+    /// A copy of the tables of the fixtures in the temporary folder, with an OMA file in 10F00 for each title of
+    /// `tracks`, whose header has its track number.
+    pub fn fixtures_copy(name: &str, tracks: &[(TitleId, u32)]) -> PathBuf {
+        let path = env::temp_dir().join(format!("rs-{}-{}", name, process::id())).join("OMGAUDIO");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        for entry in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/OMGAUDIO")).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), path.join(entry.file_name())).unwrap();
+        }
+        for &(title_id, track_number) in tracks {
+            let tag = Tag {
+                track_number: Some(track_number),
+                ..Tag::default()
+            };
+            let oma = mp3_to_oma(&mpeg_frames(4), &tag).unwrap();
+            write_file(&path.join("10F00").join(format!("1000{:04X}.OMA", title_id)), &oma).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn save_unchanged_tables() {
+        let path = fixtures_copy("database-save", FIXTURE_TRACKS);
+        let mut database = OmaDatabase::open(&path).unwrap();
+        database.save().unwrap();
+        for entry in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/OMGAUDIO")).unwrap() {
+            let entry = entry.unwrap();
+            assert!(read_file(&path.join(entry.file_name())).unwrap() == read_file(&entry.path()).unwrap());
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+/*
+
+# Keys
+
+The keys are sums of title_keys:
+- the key of an element of 03GINFXX (album_key, artist_key, genre_key) is the sum of the title_keys of the titles in its groups of 01TREEXX (an unused element has a zero key),
+- the global_key of 02TREINF is the sum of the title_keys of all the titles present in the views.
+The global_key is only written for the views which contain titles.
+
+*/
+
+use database::OmaDatabase;
+use parser::Result;
+use tree::groups;
+
+pub enum KeyLocation {
+    /// The element item_id of 03GINFXX, where XX is the view.
+    Group {
+        view: u8,
+        item_id: u16,
+    },
+    /// The element of 02TREINF describing the view.
+    Global {
+        view: u8,
+    },
+}
+
+pub struct KeyMismatch {
+    pub location: KeyLocation,
+    pub stored: u32,
+    pub expected: u32,
+}
+
+/// The sum of the title_keys of the titles present in the views.
+pub fn global_key(database: &OmaDatabase) -> u32 {
+    database.titles().iter()
+        .filter(|title| !title.positions.is_empty())
+        .fold(0, |key, title| key.wrapping_add(title.key))
+}
+
+/// The expected key of each element of 03GINFXX, where XX is the view.
+/// The groups referring to missing items and the missing titles are ignored: `verify` reports them.
+pub fn group_keys(database: &OmaDatabase, view: u8) -> Result<Vec<u32>> {
+    let ginf = database.ginfs.get(&view)
+        .ok_or_else(|| format!("No 03GINF{:02X}", view))?;
+    let tree = database.trees.get(&view)
+        .ok_or_else(|| format!("No 01TREE{:02X}", view))?;
+    let mut keys = vec![0u32; ginf.gpfb()?.len()];
+    for group in groups(tree)? {
+        let key = (group.ginf_item as usize).checked_sub(1)
+            .and_then(|index| keys.get_mut(index));
+        if let Some(key) = key {
+            for title in group.titles.iter().filter_map(|&title_id| database.title(title_id)) {
+                *key = key.wrapping_add(title.key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Compare every key stored in 03GINFXX and 02TREINF with the sums of title_keys.
+pub fn verify_keys(database: &OmaDatabase) -> Result<Vec<KeyMismatch>> {
+    let mut mismatches = vec![];
+    for &view in database.trees.keys() {
+        let keys = group_keys(database, view)?;
+        for (index, (element, &expected)) in database.ginfs[&view].gpfb()?.iter().zip(&keys).enumerate() {
+            if element.key != expected {
+                mismatches.push(KeyMismatch {
+                    location: KeyLocation::Group {
+                        view,
+                        item_id: index as u16 + 1,
+                    },
+                    stored: element.key,
+                    expected,
+                });
+            }
+        }
+    }
+
+    let expected = global_key(database);
+    for element in database.treinf.gtfb()? {
+        let has_titles = match database.trees.get(&element.view) {
+            Some(tree) => !tree.tplb()?.is_empty(),
+            None => false,
+        };
+        if element.exists() && has_titles && element.global_key != expected {
+            mismatches.push(KeyMismatch {
+                location: KeyLocation::Global {
+                    view: element.view,
+                },
+                stored: element.global_key,
+                expected,
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use database::OmaDatabase;
+    use tree::artist_albums;
+    use super::{KeyLocation, global_key, group_keys, verify_keys};
+
+    fn fixtures() -> OmaDatabase {
+        OmaDatabase::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/OMGAUDIO")).unwrap()
+    }
+
+    #[test]
+    fn report_key_mismatches() {
+        let mut database = fixtures();
+        assert!(verify_keys(&database).unwrap().is_empty());
+        let album_key = database.ginfs[&3].gpfb().unwrap()[1].key;
+        let global = global_key(&database);
+        database.ginfs.get_mut(&3).unwrap().gpfb_mut().unwrap()[1].key += 1;
+        database.treinf.gtfb_mut().unwrap()[0].global_key = 0;
+
+        let mismatches = verify_keys(&database).unwrap();
+        assert_eq!(mismatches.len(), 2);
+        match mismatches[0].location {
+            KeyLocation::Group { view, item_id } => assert_eq!((view, item_id), (3, 2)),
+            KeyLocation::Global { .. } => panic!("Expected the album key"),
+        }
+        assert_eq!((mismatches[0].stored, mismatches[0].expected), (album_key + 1, album_key));
+        match mismatches[1].location {
+            KeyLocation::Global { view } => assert_eq!(view, 1),
+            KeyLocation::Group { .. } => panic!("Expected the global key"),
+        }
+        assert_eq!((mismatches[1].stored, mismatches[1].expected), (0, global));
+    }
+
+    /// The titles of 01TREE2D belong to the albums: the artist items have a zero key.
+    #[test]
+    fn artist_album_keys() {
+        let database = fixtures();
+        let keys = group_keys(&database, 0x2D).unwrap();
+        let artists = artist_albums(&database.trees[&0x2D]).unwrap();
+        // The blank item, Archive and Radiohead.
+        assert_eq!(artists.len(), 3);
+        for artist in &artists {
+            assert_eq!(keys[artist.ginf_item as usize - 1], 0);
+            for album in &artist.albums {
+                assert_ne!(keys[album.ginf_item as usize - 1], 0);
+            }
+        }
+    }
+}
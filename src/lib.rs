@@ -0,0 +1,13 @@
+pub mod collation;
+pub mod compact;
+pub mod database;
+pub mod keys;
+pub mod menu;
+pub mod parser;
+pub mod repair;
+pub mod sort;
+pub mod storage;
+pub mod tree;
+pub mod update;
+pub mod verify;
+pub mod writer;
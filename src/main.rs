@@ -92,56 +92,178 @@ magik_key: 0x ff ff 03 90 da 10 To be studied. Present in files : 03GINF 04CNTIN
 
 */
 
-use std::fs::File;
-use std::io::Read;
+extern crate rs;
 
-mod parser;
+use std::env;
+use std::process;
 
-use parser::{Result, parse_table};
+use rs::collation::Collation;
+use rs::compact::Compaction;
+use rs::database::OmaDatabase;
+use rs::menu;
+use rs::repair::repair;
+use rs::sort::sort_views;
+use rs::update::TrackSource;
+use rs::parser::{GpfbElement, Result, TitleId};
+use rs::verify::{Severity, verify};
+
+/// The folder read by the commands which do not write, when none is given.
+const DEFAULT_PATH: &str = "../A091-E093/OMGAUDIO";
 
 fn main() {
-    let files = [
-        "../A091-E093/OMGAUDIO/01TREE01.DAT",
-        "../A091-E093/OMGAUDIO/01TREE02.DAT",
-        "../A091-E093/OMGAUDIO/01TREE03.DAT",
-        "../A091-E093/OMGAUDIO/01TREE04.DAT",
-        "../A091-E093/OMGAUDIO/02TREINF.DAT",
-        "../A091-E093/OMGAUDIO/03GINF01.DAT",
-    ];
-    for file in &files {
-        println!("***");
-        if let Err(error) = drive(file) {
-            println!("Error: {}", error);
+    let (options, args): (Vec<_>, Vec<_>) = env::args().skip(1)
+        .partition(|arg| arg.starts_with("--"));
+    // The commands which write need the folder, so that a developer dump is not changed by mistake.
+    let path = args.get(1).map(String::as_str);
+    let force = options.iter().any(|option| option == "--force");
+    let collation = collation(&options);
+    let result =
+        match (args.first().map(String::as_str), path, collation) {
+            (_, _, Err(error)) => Err(error),
+            (Some("add"), Some(path), Ok(ref collation)) if args.len() > 2 => add_tracks(path, &args[2..], collation),
+            (Some("compact"), Some(path), _) => compact(path, args.get(2).map(String::as_str) == Some("renumber")),
+            (Some("menu"), _, _) => print_menu(path.unwrap_or(DEFAULT_PATH)),
+            (Some("remove"), Some(path), Ok(ref collation)) if args.len() > 2 =>
+                remove_tracks(path, &args[2..], collation),
+            (Some("repair"), Some(path), Ok(ref collation)) => repair_database(path, collation, force),
+            (Some("sort"), Some(path), Ok(ref collation)) => sort_database(path, collation),
+            (Some("sort-name"), Some(path), Ok(ref collation)) if args.len() > 3 =>
+                set_sort_name(path, &args[2], &args[3], collation),
+            (Some("titles"), _, _) => print_titles(path.unwrap_or(DEFAULT_PATH)),
+            (Some("verify"), _, _) => print_violations(path.unwrap_or(DEFAULT_PATH)),
+            _ => {
+                println!("Usage: rs (menu|titles|verify) [OMGAUDIO_folder]");
+                println!("       rs (repair|sort) OMGAUDIO_folder");
+                println!("       rs add OMGAUDIO_folder MP3_files...");
+                println!("       rs compact OMGAUDIO_folder [renumber]");
+                println!("       rs remove OMGAUDIO_folder title_ids...");
+                println!("       rs sort-name OMGAUDIO_folder artist sort_name");
+                println!("repair refuses to drop many titles, like when 10F0X is missing, unless given --force.");
+                println!("The commands which sort the views (add, remove, repair, sort, sort-name) take the options:");
+                println!("  --articles=The,Les  the leading words ignored in the artist names (default: The)");
+                println!("  --keep-accents      do not compare the accented letters as their base letter");
+                println!("  --keep-case         do not compare the uppercase letters as the lowercase ones");
+                println!("  --keep-kana         do not compare the katakana as the hiragana");
+                process::exit(1);
+            },
+        };
+    if let Err(error) = result {
+        println!("Error: {}", error);
+        process::exit(1);
+    }
+}
+
+fn add_tracks(path: &str, files: &[String], collation: &Collation) -> Result<()> {
+    let mut database = open(path, collation)?;
+    let mut sources = vec![];
+    for file in files {
+        sources.push(TrackSource::from_mp3(file)?);
+    }
+    let title_ids = database.add_tracks(&sources)?;
+    database.save()?;
+    for (file, title_id) in files.iter().zip(title_ids) {
+        println!("{:04X}\t{}", title_id, file);
+    }
+    Ok(())
+}
+
+/// The collation of the options, like --articles=The,Les or --keep-case.
+fn collation(options: &[String]) -> Result<Collation> {
+    let mut collation = Collation::default();
+    for option in options {
+        match option.as_str() {
+            "--keep-accents" => collation.ignore_accents = false,
+            "--keep-case" => collation.ignore_case = false,
+            "--keep-kana" => collation.ignore_kana = false,
+            // Not an option of the collation, read by main.
+            "--force" => (),
+            _ if option.starts_with("--articles=") => {
+                collation.articles = option["--articles=".len()..].split(',')
+                    .filter(|article| !article.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            },
+            _ => return Err(format!("Unknown option {}", option)),
         }
     }
+    Ok(collation)
+}
+
+fn compact(path: &str, renumber: bool) -> Result<()> {
+    let mut database = OmaDatabase::open(path)?;
+    let compaction = if renumber { Compaction::Renumber } else { Compaction::Free };
+    let free = database.compact(compaction)?;
+    database.save()?;
+    println!("{} free title_ids", free.len());
+    Ok(())
 }
 
-fn drive(filename: &str) -> Result<()> {
-    let mut file = File::open(filename)
-        .map_err(|err| err.to_string())?;
-    let mut buffer =  vec![];
-    file.read_to_end(&mut buffer)
-        .map_err(|err| err.to_string())?;
-    let table = parse_table(&buffer)?;
-    println!("{}", String::from_utf8_lossy(&table.name));
-    println!("{}", table.class_count);
-
-    println!("{}", String::from_utf8_lossy(&table.class_descriptions[0].name));
-    println!("{}", table.class_descriptions[0].address);
-    println!("{}", table.class_descriptions[0].len);
-    if table.class_descriptions.len() > 1 {
-        println!("{}", String::from_utf8_lossy(&table.class_descriptions[1].name));
-        println!("{}", table.class_descriptions[1].address);
-        println!("{}", table.class_descriptions[1].len);
+fn print_menu(path: &str) -> Result<()> {
+    let database = OmaDatabase::open(path)?;
+    print!("{}", menu::render(&database)?);
+    Ok(())
+}
+
+fn print_violations(path: &str) -> Result<()> {
+    let database = OmaDatabase::open(path)?;
+    let violations = verify(&database)?;
+    for violation in &violations {
+        println!("{}", violation);
+    }
+    if violations.iter().any(|violation| violation.severity == Severity::Error) {
+        process::exit(2);
     }
+    Ok(())
+}
 
-    println!("{}", String::from_utf8_lossy(&table.classes[0].name));
-    println!("{}", &table.classes[0].element_count);
-    println!("{}", &table.classes[0].element_length);
-    if table.classes.len() > 1 {
-        println!("{}", String::from_utf8_lossy(&table.classes[1].name));
-        println!("{}", &table.classes[1].element_count);
-        println!("{}", &table.classes[1].element_length);
+fn print_titles(path: &str) -> Result<()> {
+    let database = OmaDatabase::open(path)?;
+    for title in database.titles() {
+        if title.positions.is_empty() {
+            continue;
+        }
+        println!("{:04X}\t{}\t{}\t{}\t{}", title.id, title.name, name(database.artist(title))?, name(database.album(title))?,
+            name(database.genre(title))?);
     }
     Ok(())
 }
+
+fn remove_tracks(path: &str, title_ids: &[String], collation: &Collation) -> Result<()> {
+    let mut database = open(path, collation)?;
+    let mut ids = vec![];
+    for title_id in title_ids {
+        ids.push(TitleId::from_str_radix(title_id, 16)
+            .map_err(|_| format!("Invalid title_id {}", title_id))?);
+    }
+    database.remove_tracks(&ids)?;
+    database.save()
+}
+
+fn repair_database(path: &str, collation: &Collation, force: bool) -> Result<()> {
+    let mut database = open(path, collation)?;
+    repair(&mut database, force)?;
+    database.save()
+}
+
+fn set_sort_name(path: &str, artist: &str, sort_name: &str, collation: &Collation) -> Result<()> {
+    let mut database = open(path, collation)?;
+    database.set_sort_name(artist, sort_name)?;
+    database.save()
+}
+
+fn sort_database(path: &str, collation: &Collation) -> Result<()> {
+    let mut database = open(path, collation)?;
+    sort_views(&mut database)?;
+    database.save()
+}
+
+/// Open the database, sorting its views with the collation.
+fn open(path: &str, collation: &Collation) -> Result<OmaDatabase> {
+    let mut database = OmaDatabase::open(path)?;
+    database.collation = collation.clone();
+    Ok(database)
+}
+
+fn name(element: Option<&GpfbElement>) -> Result<String> {
+    element.map_or(Ok(String::new()), |element| element.text(b"TIT2"))
+}
@@ -0,0 +1,104 @@
+use std::fmt::Write;
+
+use database::{OmaDatabase, UPLOADED_VIEW};
+use parser::{GpfbElement, Result, TitleId};
+use tree::{artist_albums, groups};
+
+/// Render the menus of the player, as shown from the TREE and GINF tables:
+/// groups in GPLB order, titles in TPLB order, unused groups hidden.
+/// Nothing is sorted here: the player shows the order of the tables, so a wrong sort (see `sort`) shows as is.
+pub fn render(database: &OmaDatabase) -> Result<String> {
+    let mut output = String::new();
+    let gtlb = database.gtrlst.gtlb()?;
+    for (&view, tree) in &database.trees {
+        let ginf = database.ginfs[&view].gpfb()?;
+        let tags = gtlb.iter()
+            .find(|element| element.file_ref == view as u16)
+            .map(|element| element.tags.as_slice())
+            .unwrap_or(&[]);
+        if tags.len() == 2 {
+            writeln!(output, "{} ({:02X})", caption(view, tags), view).unwrap();
+            for artist in artist_albums(tree)? {
+                if artist.albums.is_empty() {
+                    continue;
+                }
+                writeln!(output, "  {}", group_name(ginf, artist.ginf_item)?).unwrap();
+                for album in artist.albums {
+                    writeln!(output, "    {}", group_name(ginf, album.ginf_item)?).unwrap();
+                    for &title_id in &album.titles {
+                        writeln!(output, "      {}", title_name(database, title_id)).unwrap();
+                    }
+                }
+            }
+        }
+        else {
+            let groups = groups(tree)?;
+            if groups.is_empty() {
+                continue;
+            }
+            writeln!(output, "{} ({:02X})", caption(view, tags), view).unwrap();
+            for group in groups.iter().filter(|group| group.used) {
+                writeln!(output, "  {}", group_name(ginf, group.ginf_item)?).unwrap();
+                for &title_id in &group.titles {
+                    writeln!(output, "    {}", title_name(database, title_id)).unwrap();
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn caption(view: u8, tags: &[Vec<u8>]) -> String {
+    if tags.is_empty() {
+        let caption =
+            if view == UPLOADED_VIEW {
+                "Uploaded"
+            }
+            else {
+                "Untagged"
+            };
+        return caption.to_string();
+    }
+    let names: Vec<_> = tags.iter()
+        .map(|tag| match tag.as_slice() {
+            b"TALB" => "Album".to_string(),
+            b"TCON" => "Genre".to_string(),
+            b"TPE1" => "Artist".to_string(),
+            _ => String::from_utf8_lossy(tag).into_owned(),
+        })
+        .collect();
+    names.join(" > ")
+}
+
+fn group_name(ginf: &[GpfbElement], item_id: u16) -> Result<String> {
+    let element = (item_id as usize).checked_sub(1)
+        .and_then(|index| ginf.get(index))
+        .ok_or_else(|| format!("No item {} in 03GINFXX", item_id))?;
+    element.text(b"TIT2")
+}
+
+fn title_name(database: &OmaDatabase, title_id: TitleId) -> String {
+    match database.title(title_id) {
+        Some(title) => title.name.clone(),
+        None => format!("<missing title {:04X}>", title_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use database::OmaDatabase;
+    use super::{caption, render};
+
+    #[test]
+    fn captions() {
+        assert_eq!(caption(0x01, &[]), "Uploaded");
+        assert_eq!(caption(0x22, &[]), "Untagged");
+        assert_eq!(caption(0x2D, &[b"TPE1".to_vec(), b"TALB".to_vec()]), "Artist > Album");
+    }
+
+    #[test]
+    fn render_menus() {
+        let database = OmaDatabase::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/OMGAUDIO")).unwrap();
+        assert_eq!(render(&database).unwrap(), include_str!("../tests/fixtures/menu.txt"));
+    }
+}
@@ -1,7 +1,16 @@
 use std::result;
 
+pub mod music;
+pub mod text;
+
 pub type Result<T> = result::Result<T, String>;
 
+/// The place of a title in 04CNTINF, starting at 1.
+pub type TitleId = u16;
+
+/// The size of the frames of the elements which are written, as SonicStage does.
+pub const FRAME_SIZE: u16 = 0x80;
+
 /*
 
 # Table
@@ -44,30 +53,269 @@ This can't be described here, it depends on each class.
 
 // TODO: remove fields only useful for parsing?
 
+/// The X + 2 field of a GPLB element.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Association {
+    Unused,
+    /// 0x0100: a used group. In 01TREE2D, the group is an artist.
+    Used,
+    /// 0x0200: an album of 01TREE2D.
+    Album,
+    Other(u16),
+}
+
+impl Association {
+    pub fn is_used(self) -> bool {
+        self != Association::Unused
+    }
+
+    pub fn value(self) -> u16 {
+        match self {
+            Association::Unused => 0x0000,
+            Association::Used => 0x0100,
+            Association::Album => 0x0200,
+            Association::Other(value) => value,
+        }
+    }
+}
+
+impl From<u16> for Association {
+    fn from(value: u16) -> Self {
+        match value {
+            0x0000 => Association::Unused,
+            0x0100 => Association::Used,
+            0x0200 => Association::Album,
+            _ => Association::Other(value),
+        }
+    }
+}
+
 pub struct Class {
     pub name: Vec<u8>,
     pub element_count: u16,
     pub element_length: u16,
+    /// The bytes from X + 8 to X + 16 of the class header.
+    pub header: Vec<u8>,
     pub kind: ClassKind,
+    /// The bytes after the last element, up to the end of the class.
+    pub padding: Vec<u8>,
+}
+
+impl Class {
+    /// Set the number repeated at X + 0A of the class header.
+    pub fn set_repeated_count(&mut self, count: u16) {
+        self.header[2] = (count >> 8) as u8;
+        self.header[3] = count as u8;
+    }
 }
 
 pub struct ClassDescription {
     pub name: Vec<u8>,
     pub address: u32,
     pub len: u32,
+    pub reserved: Vec<u8>,
 }
 
 pub enum ClassKind {
+    Cilb(Vec<CilbElement>),
+    Cnfb(Vec<CnfbElement>),
     Gplb(Vec<GplbElement>),
-    Gpfb(),
-    Gtfb(),
+    Gpfb(Vec<GpfbElement>),
+    Gtfb(Vec<GtfbElement>),
+    Gtlb(Vec<GtlbElement>),
+    Sysb(Vec<SysbElement>),
     Tplb(Vec<TplbElement>),
 }
 
+impl ClassKind {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            ClassKind::Cilb(ref elements) => elements.len(),
+            ClassKind::Cnfb(ref elements) => elements.len(),
+            ClassKind::Gplb(ref elements) => elements.len(),
+            ClassKind::Gpfb(ref elements) => elements.len(),
+            ClassKind::Gtfb(ref elements) => elements.len(),
+            ClassKind::Gtlb(ref elements) => elements.len(),
+            ClassKind::Sysb(ref elements) => elements.len(),
+            ClassKind::Tplb(ref elements) => elements.len(),
+        }
+    }
+}
+
+pub struct CilbElement {
+    pub title_id: TitleId,
+    pub drmed_oma_key: Vec<u8>,
+    pub trailing: Vec<u8>,
+}
+
+pub struct CnfbElement {
+    pub reserved: Vec<u8>,
+    pub protection: u16,
+    pub file_properties: u32,
+    pub title_key: u32,
+    pub part_count: u16,
+    pub part_size: u16,
+    pub frames: Vec<Frame>,
+    pub trailing: Vec<u8>,
+}
+
+impl CnfbElement {
+    pub fn frame(&self, tag: &[u8]) -> Option<&Frame> {
+        find_frame(&self.frames, tag)
+    }
+
+    /// Replace the text of the frame, which must exist.
+    pub fn set_text(&mut self, tag: &[u8], value: &str) -> Result<()> {
+        set_frame_text(&mut self.frames, tag, value)
+    }
+
+    /// The text to sort the artist of the title by (the TSOP frame), or an empty string.
+    pub fn sort_name(&self) -> Result<String> {
+        self.text(b"TSOP")
+    }
+
+    /// The text of the frame, or an empty string when there is no such frame.
+    pub fn text(&self, tag: &[u8]) -> Result<String> {
+        frame_text(&self.frames, tag)
+    }
+}
+
+/// A tagged part of an element: a 4-byte tag (like TIT2), a 2-byte encoding and the content.
+#[derive(Clone)]
+pub struct Frame {
+    pub tag: Vec<u8>,
+    pub encoding: u16,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Create a frame of `size` bytes containing the text in 16-bits encoding.
+    /// A text which does not fit in the frame is cut on purpose after its last character which fits, so that no
+    /// character (or surrogate pair) is split.
+    /// Panics when `size` is less than 6 bytes, the size of the tag and of the encoding.
+    pub fn new(tag: &[u8], value: &str, size: u16) -> Frame {
+        assert!(size >= 6, "A frame of {} bytes has no room for its tag and encoding", size);
+        let len = size as usize - 6;
+        let mut end = value.len();
+        while value[..end].encode_utf16().count() * 2 > len {
+            end = value[..end].char_indices().last().map(|(index, _)| index).unwrap_or(0);
+        }
+        let mut data = text::encode_utf16(&value[..end]);
+        data.resize(len, 0);
+        Frame {
+            tag: tag.to_vec(),
+            encoding: text::UTF16_BE,
+            data,
+        }
+    }
+
+    pub fn text(&self) -> Result<String> {
+        text::decode(self.encoding, &self.data)
+    }
+}
+
+/// An element of 03GINFXX: an album, an artist, a genre or an artist-album item, depending on the file.
+/// The key is the album_key, artist_key or genre_key.
+pub struct GpfbElement {
+    pub magic_key: Vec<u8>,
+    pub key: u32,
+    pub part_count: u16,
+    pub part_size: u16,
+    pub frames: Vec<Frame>,
+    pub trailing: Vec<u8>,
+}
+
+impl GpfbElement {
+    pub fn frame(&self, tag: &[u8]) -> Option<&Frame> {
+        find_frame(&self.frames, tag)
+    }
+
+    /// Replace the text of the frame, which must exist.
+    pub fn set_text(&mut self, tag: &[u8], value: &str) -> Result<()> {
+        set_frame_text(&mut self.frames, tag, value)
+    }
+
+    /// The text to sort the artist of the item by (the TSOP frame of 03GINF01), or an empty string.
+    pub fn sort_name(&self) -> Result<String> {
+        self.text(b"TSOP")
+    }
+
+    /// The text of the frame, or an empty string when there is no such frame.
+    pub fn text(&self, tag: &[u8]) -> Result<String> {
+        frame_text(&self.frames, tag)
+    }
+}
+
 pub struct GplbElement {
     pub id: u16,
-    pub association: u16,
+    pub association: Association,
     pub title_id: u16,
+    pub reserved: Vec<u8>,
+}
+
+/// An element of 02TREINF, describing the view stored in the files 01TREEXX and 03GINFXX where XX is `view`.
+pub struct GtfbElement {
+    pub view: u8,
+    pub magic_key: Vec<u8>,
+    pub global_key: u32,
+    pub part_count: u16,
+    pub part_size: u16,
+    pub frames: Vec<Frame>,
+    pub trailing: Vec<u8>,
+}
+
+impl GtfbElement {
+    pub fn caption(&self) -> Result<Option<String>> {
+        self.frame(b"TIT2")
+            .map(Frame::text)
+            .transpose()
+    }
+
+    pub fn frame(&self, tag: &[u8]) -> Option<&Frame> {
+        find_frame(&self.frames, tag)
+    }
+
+    /// Whether the element describes a view, with a TIT2 frame.
+    pub fn exists(&self) -> bool {
+        self.part_count != 0
+    }
+
+    /// Whether the view is used: its global_key is only written then, and not for the view 22 which exists.
+    pub fn is_used(&self) -> bool {
+        self.exists() && self.global_key != 0
+    }
+
+    /// The sort descriptors (like "STD_TPE1") written in the frames of the element.
+    pub fn sort_descriptors(&self) -> Result<Vec<String>> {
+        let mut descriptors = vec![];
+        for frame in &self.frames {
+            let text = frame.text()?;
+            if text.starts_with("STD_") {
+                descriptors.push(text);
+            }
+        }
+        Ok(descriptors)
+    }
+}
+
+/// An element of 00GTRLST, telling which ID3 tags the view stored in the files 01TREEXX and 03GINFXX is keyed on,
+/// where XX is `file_ref`.
+pub struct GtlbElement {
+    pub file_ref: u16,
+    pub unknown1: u16,
+    pub reserved1: Vec<u8>,
+    pub reserved2: Vec<u8>,
+    pub tags: Vec<Vec<u8>>,
+    /// The bytes after the tags, like "TRNOTTCCTTCC" for the element 0x2D.
+    pub trailing: Vec<u8>,
+}
+
+pub struct SysbElement {
+    pub data: Vec<u8>,
 }
 
 pub struct Table {
@@ -75,10 +323,184 @@ pub struct Table {
     pub class_descriptions: Vec<ClassDescription>,
     pub class_count: u8,
     pub name: Vec<u8>,
+    /// The bytes after the number of classes, up to the first class description.
+    pub reserved: Vec<u8>,
+    /// The bytes after the last class, up to the end of the file.
+    pub trailing: Vec<u8>,
+}
+
+impl Table {
+    pub fn cilb(&self) -> Result<&[CilbElement]> {
+        match self.class(b"CILB")?.kind {
+            ClassKind::Cilb(ref elements) => Ok(elements),
+            _ => Err(self.unexpected_kind(b"CILB")),
+        }
+    }
+
+    pub fn class(&self, name: &[u8]) -> Result<&Class> {
+        self.classes.iter()
+            .find(|class| class.name == name)
+            .ok_or_else(|| format!("No class {} in table {}", String::from_utf8_lossy(name), String::from_utf8_lossy(&self.name)))
+    }
+
+    pub fn cnfb(&self) -> Result<&[CnfbElement]> {
+        match self.class(b"CNFB")?.kind {
+            ClassKind::Cnfb(ref elements) => Ok(elements),
+            _ => Err(self.unexpected_kind(b"CNFB")),
+        }
+    }
+
+    pub fn gpfb(&self) -> Result<&[GpfbElement]> {
+        match self.class(b"GPFB")?.kind {
+            ClassKind::Gpfb(ref elements) => Ok(elements),
+            _ => Err(self.unexpected_kind(b"GPFB")),
+        }
+    }
+
+    pub fn gplb(&self) -> Result<&[GplbElement]> {
+        match self.class(b"GPLB")?.kind {
+            ClassKind::Gplb(ref elements) => Ok(elements),
+            _ => Err(self.unexpected_kind(b"GPLB")),
+        }
+    }
+
+    pub fn gtfb(&self) -> Result<&[GtfbElement]> {
+        match self.class(b"GTFB")?.kind {
+            ClassKind::Gtfb(ref elements) => Ok(elements),
+            _ => Err(self.unexpected_kind(b"GTFB")),
+        }
+    }
+
+    pub fn gtlb(&self) -> Result<&[GtlbElement]> {
+        match self.class(b"GTLB")?.kind {
+            ClassKind::Gtlb(ref elements) => Ok(elements),
+            _ => Err(self.unexpected_kind(b"GTLB")),
+        }
+    }
+
+    pub fn tplb(&self) -> Result<&[TplbElement]> {
+        match self.class(b"TPLB")?.kind {
+            ClassKind::Tplb(ref elements) => Ok(elements),
+            _ => Err(self.unexpected_kind(b"TPLB")),
+        }
+    }
+
+    pub fn class_mut(&mut self, name: &[u8]) -> Result<&mut Class> {
+        let table_name = String::from_utf8_lossy(&self.name).into_owned();
+        self.classes.iter_mut()
+            .find(|class| class.name == name)
+            .ok_or_else(|| format!("No class {} in table {}", String::from_utf8_lossy(name), table_name))
+    }
+
+    pub fn cilb_mut(&mut self) -> Result<&mut Vec<CilbElement>> {
+        let error = self.unexpected_kind(b"CILB");
+        match self.class_mut(b"CILB")?.kind {
+            ClassKind::Cilb(ref mut elements) => Ok(elements),
+            _ => Err(error),
+        }
+    }
+
+    pub fn cnfb_mut(&mut self) -> Result<&mut Vec<CnfbElement>> {
+        let error = self.unexpected_kind(b"CNFB");
+        match self.class_mut(b"CNFB")?.kind {
+            ClassKind::Cnfb(ref mut elements) => Ok(elements),
+            _ => Err(error),
+        }
+    }
+
+    pub fn gpfb_mut(&mut self) -> Result<&mut Vec<GpfbElement>> {
+        let error = self.unexpected_kind(b"GPFB");
+        match self.class_mut(b"GPFB")?.kind {
+            ClassKind::Gpfb(ref mut elements) => Ok(elements),
+            _ => Err(error),
+        }
+    }
+
+    pub fn gplb_mut(&mut self) -> Result<&mut Vec<GplbElement>> {
+        let error = self.unexpected_kind(b"GPLB");
+        match self.class_mut(b"GPLB")?.kind {
+            ClassKind::Gplb(ref mut elements) => Ok(elements),
+            _ => Err(error),
+        }
+    }
+
+    pub fn gtfb_mut(&mut self) -> Result<&mut Vec<GtfbElement>> {
+        let error = self.unexpected_kind(b"GTFB");
+        match self.class_mut(b"GTFB")?.kind {
+            ClassKind::Gtfb(ref mut elements) => Ok(elements),
+            _ => Err(error),
+        }
+    }
+
+    pub fn tplb_mut(&mut self) -> Result<&mut Vec<TplbElement>> {
+        let error = self.unexpected_kind(b"TPLB");
+        match self.class_mut(b"TPLB")?.kind {
+            ClassKind::Tplb(ref mut elements) => Ok(elements),
+            _ => Err(error),
+        }
+    }
+
+    /// Update the element counts, the lengths and the addresses of the classes after their elements changed.
+    /// A class is (Number_of_elements * Length_of_one_element) + 0x10 long, rounded up to end with 0,
+    /// except the classes of constant length (GPLB, GTFB) which only grow when their elements do not fit anymore.
+    pub fn relayout(&mut self) {
+        let mut address = self.class_descriptions.first()
+            .map(|description| description.address)
+            .unwrap_or(0);
+        for (class, description) in self.classes.iter_mut().zip(self.class_descriptions.iter_mut()) {
+            class.element_count = class.kind.len() as u16;
+            let content_len = 0x10 + class.element_count as u32 * class.element_length as u32;
+            let mut len = (content_len + 0xF) & !0xF;
+            if class.name == b"GPLB" || class.name == b"GTFB" {
+                len = len.max(description.len);
+            }
+            class.padding = vec![0; (len - content_len) as usize];
+            description.address = address;
+            description.len = len;
+            address += len;
+        }
+    }
+
+    fn unexpected_kind(&self, name: &[u8]) -> String {
+        format!("Unexpected kind for class {} in table {}", String::from_utf8_lossy(name), String::from_utf8_lossy(&self.name))
+    }
 }
 
 pub struct TplbElement {
-    pub title_id: u16,
+    pub title_id: TitleId,
+}
+
+/// Check that 05CIDLST lists as many titles as 04CNTINF, as both are indexed by title_id.
+pub fn check_title_lists(cntinf: &Table, cidlst: &Table) -> Result<()> {
+    let cnfb = cntinf.class(b"CNFB")?;
+    let cilb = cidlst.class(b"CILB")?;
+    if cnfb.element_count == cilb.element_count {
+        Ok(())
+    }
+    else {
+        Err(format!("04CNTINF lists {} titles, but 05CIDLST lists {} titles", cnfb.element_count, cilb.element_count))
+    }
+}
+
+pub fn find_frame<'a>(frames: &'a [Frame], tag: &[u8]) -> Option<&'a Frame> {
+    frames.iter().find(|frame| frame.tag == tag)
+}
+
+/// The text of the frame, or an empty string when there is no such frame.
+fn frame_text(frames: &[Frame], tag: &[u8]) -> Result<String> {
+    find_frame(frames, tag)
+        .map(Frame::text)
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+/// Replace the frame with a frame of the same size containing the text.
+fn set_frame_text(frames: &mut [Frame], tag: &[u8], value: &str) -> Result<()> {
+    let frame = frames.iter_mut()
+        .find(|frame| frame.tag == tag)
+        .ok_or_else(|| format!("No {} frame", String::from_utf8_lossy(tag)))?;
+    *frame = Frame::new(tag, value, frame.data.len() as u16 + 6);
+    Ok(())
 }
 
 pub fn parse_table(buffer: &[u8]) -> Result<Table> {
@@ -100,17 +522,27 @@ impl<'a> Parser<'a> {
     }
 
     fn klass(&mut self, class_description: &ClassDescription) -> Result<Class> {
+        let address = class_description.address as usize;
         let current_index = self.index;
-        self.take(class_description.address as usize - current_index)?;
+        if address < current_index {
+            return Err(format!("Class {} at address {:x} overlaps the previous class", String::from_utf8_lossy(&class_description.name), address));
+        }
+        self.take(address - current_index)?;
         let name = self.take(4)?.to_vec();
         let element_count = self.u16()?;
         let element_length = self.u16()?;
-        let kind = self.kind(&name, element_count)?;
+        let header = self.take(8)?.to_vec();
+        let kind = self.kind(&name, element_count, element_length)?;
+        let end = (address + class_description.len as usize).min(self.buffer.len());
+        let current_index = self.index;
+        let padding = self.take(end.saturating_sub(current_index))?.to_vec();
         Ok(Class {
             name,
             element_count,
             element_length,
+            header,
             kind,
+            padding,
         })
     }
 
@@ -118,31 +550,126 @@ impl<'a> Parser<'a> {
         let name = self.take(4)?.to_vec();
         let address = self.u32()?;
         let len = self.u32()?;
-        self.take(4)?;
+        let reserved = self.take(4)?.to_vec();
         Ok(ClassDescription {
             name,
             address,
             len,
+            reserved,
+        })
+    }
+
+    fn frame(&mut self, size: u16) -> Result<Frame> {
+        if size < 6 {
+            return Err(format!("Frame size {} is too small", size));
+        }
+        let tag = self.take(4)?.to_vec();
+        let encoding = self.u16()?;
+        let data = self.take(size as usize - 6)?.to_vec();
+        Ok(Frame {
+            tag,
+            encoding,
+            data,
         })
     }
 
-    fn kind(&mut self, name: &[u8], element_count: u16) -> Result<ClassKind> {
+    fn frames(&mut self, part_count: u16, part_size: u16) -> Result<Vec<Frame>> {
+        let mut frames = vec![];
+        for _ in 0..part_count {
+            frames.push(self.frame(part_size)?);
+        }
+        Ok(frames)
+    }
+
+    fn kind(&mut self, name: &[u8], element_count: u16, element_length: u16) -> Result<ClassKind> {
         match name {
+            b"CILB" => {
+                let mut elements = vec![];
+                for index in 0..element_count {
+                    let mut parser = Parser::new(self.take(element_length as usize)?);
+                    let drmed_oma_key = parser.take(0x18)?.to_vec();
+                    elements.push(CilbElement {
+                        title_id: index + 1,
+                        drmed_oma_key,
+                        trailing: parser.rest(),
+                    });
+                }
+                Ok(ClassKind::Cilb(elements))
+            },
+            b"CNFB" => {
+                let mut elements = vec![];
+                for _ in 0..element_count {
+                    let mut parser = Parser::new(self.take(element_length as usize)?);
+                    let reserved = parser.take(2)?.to_vec();
+                    let protection = parser.u16()?;
+                    let file_properties = parser.u32()?;
+                    let title_key = parser.u32()?;
+                    let part_count = parser.u16()?;
+                    let part_size = parser.u16()?;
+                    let frames = parser.frames(part_count, part_size)?;
+                    elements.push(CnfbElement {
+                        reserved,
+                        protection,
+                        file_properties,
+                        title_key,
+                        part_count,
+                        part_size,
+                        frames,
+                        trailing: parser.rest(),
+                    });
+                }
+                Ok(ClassKind::Cnfb(elements))
+            },
+            b"GTLB" => {
+                let mut elements = vec![];
+                for _ in 0..element_count {
+                    let mut parser = Parser::new(self.take(element_length as usize)?);
+                    let file_ref = parser.u16()?;
+                    let unknown1 = parser.u16()?;
+                    let reserved1 = parser.take(12)?.to_vec();
+                    let tag_count = parser.u16()?;
+                    let reserved2 = parser.take(2)?.to_vec();
+                    let mut tags = vec![];
+                    for _ in 0..tag_count {
+                        tags.push(parser.take(4)?.to_vec());
+                    }
+                    elements.push(GtlbElement {
+                        file_ref,
+                        unknown1,
+                        reserved1,
+                        reserved2,
+                        tags,
+                        trailing: parser.rest(),
+                    });
+                }
+                Ok(ClassKind::Gtlb(elements))
+            },
             b"GPLB" => {
                 let mut elements = vec![];
                 for _ in 0..element_count {
                     let id = self.u16()?;
-                    let association = self.u16()?;
+                    let association = Association::from(self.u16()?);
                     let title_id = self.u16()?;
-                    self.take(2)?;
+                    let reserved = self.take(2)?.to_vec();
                     elements.push(GplbElement {
                         id,
                         association,
                         title_id,
+                        reserved,
                     });
                 }
                 Ok(ClassKind::Gplb(elements))
             },
+            b"SYSB" => {
+                let mut elements = vec![];
+                for _ in 0..element_count {
+                    let data = self.take(element_length as usize)?.to_vec();
+                    elements.push(SysbElement {
+                        data,
+                    });
+                }
+                Ok(ClassKind::Sysb(elements))
+            },
             b"TPLB" => {
                 let mut elements = vec![];
                 for _ in 0..element_count {
@@ -154,49 +681,45 @@ impl<'a> Parser<'a> {
                 Ok(ClassKind::Tplb(elements))
             },
             b"GTFB" => {
-                let _global_key = self.take(12)?.to_vec();
-                let _contains_one_subsection = self.u16()?;
-                let _subsection_length = self.u16()?;
-                // TODO
-                Ok(ClassKind::Gtfb())
+                let mut elements = vec![];
+                for index in 0..element_count {
+                    let mut parser = Parser::new(self.take(element_length as usize)?);
+                    let magic_key = parser.take(8)?.to_vec();
+                    let global_key = parser.u32()?;
+                    let part_count = parser.u16()?;
+                    let part_size = parser.u16()?;
+                    let frames = parser.frames(part_count, part_size)?;
+                    elements.push(GtfbElement {
+                        view: (index + 1) as u8,
+                        magic_key,
+                        global_key,
+                        part_count,
+                        part_size,
+                        frames,
+                        trailing: parser.rest(),
+                    });
+                }
+                Ok(ClassKind::Gtfb(elements))
             },
             b"GPFB" => {
-                let _magic_key = self.take(8)?.to_vec();
-                let _album_key = self.u32()?;
-                let _count_size = self.u32()?;
-
-                self.u32()?; // FIXME: should not be necessary.
-                self.u32()?; // FIXME: should not be necessary.
-                self.eat(b"TIT2")?;
-                self.u16()?;
-                let album_name = self.take(122)?.to_vec();
-                println!("{}", String::from_utf8_lossy(&album_name));
-
-                self.eat(b"TPE1")?;
-                self.u16()?;
-                let artist_name = self.take(122)?.to_vec();
-                println!("{}", String::from_utf8_lossy(&artist_name));
-
-                self.eat(b"TCON")?;
-                self.u16()?;
-                let genre = self.take(122)?.to_vec();
-                println!("{}", String::from_utf8_lossy(&genre));
-
-                self.eat(b"TSOP")?;
-                self.u16()?;
-                let _un1 = self.take(122)?.to_vec();
-
-                self.eat(b"PICP")?;
-                self.u16()?;
-                let _un2 = self.take(122)?.to_vec();
-
-                self.eat(b"PIC0")?;
-                self.u16()?;
-                let _un3 = self.take(122)?.to_vec();
-
-                // TODO
-
-                Ok(ClassKind::Gpfb())
+                let mut elements = vec![];
+                for _ in 0..element_count {
+                    let mut parser = Parser::new(self.take(element_length as usize)?);
+                    let magic_key = parser.take(8)?.to_vec();
+                    let key = parser.u32()?;
+                    let part_count = parser.u16()?;
+                    let part_size = parser.u16()?;
+                    let frames = parser.frames(part_count, part_size)?;
+                    elements.push(GpfbElement {
+                        magic_key,
+                        key,
+                        part_count,
+                        part_size,
+                        frames,
+                        trailing: parser.rest(),
+                    });
+                }
+                Ok(ClassKind::Gpfb(elements))
             },
             _ => Err(format!("Unknown class kind {}", String::from_utf8_lossy(name))),
         }
@@ -207,20 +730,23 @@ impl<'a> Parser<'a> {
         self.eat_u32(0x01010000)?;
         let class_count = self.u8()?;
         let current_index = self.index;
-        self.take(16 - current_index)?;
+        let reserved = self.take(16 - current_index)?.to_vec();
         let mut classes = vec![];
         let mut class_descriptions = vec![];
         for _ in 0..class_count {
             class_descriptions.push(self.class_description()?);
         }
         for class_description in &class_descriptions {
-            classes.push(self.klass(&class_description)?);
+            classes.push(self.klass(class_description)?);
         }
+        let trailing = self.rest();
         Ok(Table {
             classes,
             class_descriptions,
             class_count,
             name,
+            reserved,
+            trailing,
         })
     }
 
@@ -245,7 +771,13 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn take(&mut self, len: usize) -> Result<&[u8]> {
+    fn rest(&mut self) -> Vec<u8> {
+        let index = self.index;
+        self.index = self.buffer.len();
+        self.buffer[index..].to_vec()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
         if self.buffer.len() - self.index >= len {
             let index = self.index;
             self.index += len;
@@ -998,6 +1530,7 @@ Length of one element	0x90
 Element header
 Adress	Length	Value	Comment
 X (the first adress of the class)	12 bytes	global_key	Is written only if the element is in used (and isn't write for element 22 that exist, but isn't used)
+			The first 8 bytes are read as a magic_key and the last 4 bytes as the global_key, like the element header of GPFB
 X + 12	2 bytes	0x00 01	Means that the element contains only one subsection, Is written only if the elemen is in used
 X + 14	2 bytes	0x00 80	Means that the subsection's length is 0x80, Is written only if the elemen is in used
 
@@ -1468,3 +2001,90 @@ If a title has been added (crushing the one before)  into 04CNTINF, its title_re
 As you don't have to do anything when a title is deleted for 04CNTINF, you don't have to do anything to this file.
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, check_title_lists, parse_table};
+
+    #[test]
+    fn cut_frame_text() {
+        let frame = Frame::new(b"TIT2", "Pyramid song", 0x80);
+        assert_eq!(frame.data.len(), 0x80 - 6);
+        assert_eq!(frame.text().unwrap(), "Pyramid song");
+
+        // 8 bytes of data: "ab" and the surrogate pair of 𝄞, but not the following "c".
+        assert_eq!(Frame::new(b"TIT2", "ab\u{1D11E}c", 14).text().unwrap(), "ab\u{1D11E}");
+        // 6 bytes of data: the surrogate pair does not fit after "ab", so it is not split.
+        assert_eq!(Frame::new(b"TIT2", "ab\u{1D11E}c", 12).text().unwrap(), "ab");
+    }
+
+    #[test]
+    fn treinf_views() {
+        let treinf = parse_table(include_bytes!("../../tests/fixtures/OMGAUDIO/02TREINF.DAT")).unwrap();
+        let elements = treinf.gtfb().unwrap();
+        let view = |view: u8| elements.iter().find(|element| element.view == view).unwrap();
+        assert_eq!(elements.len(), 0x2D);
+        assert!(elements.iter().enumerate().all(|(index, element)| element.view as usize == index + 1));
+        assert!(view(1).is_used());
+        assert_eq!(view(1).caption().unwrap().unwrap(), "");
+        assert!(view(0x22).exists() && !view(0x22).is_used());
+        assert!(!view(0x21).exists());
+        assert_eq!(view(0x2D).caption().unwrap().unwrap(), "STD_TPE1");
+        assert_eq!(view(0x2D).sort_descriptors().unwrap(), vec!["STD_TPE1"]);
+        assert!(view(1).sort_descriptors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cntinf_titles() {
+        let cntinf = parse_table(include_bytes!("../../tests/fixtures/OMGAUDIO/04CNTINF.DAT")).unwrap();
+        let elements = cntinf.cnfb().unwrap();
+        assert_eq!(elements.len(), 10);
+        let element = &elements[0];
+        assert_eq!(element.protection, 0xFFFF);
+        assert_eq!(element.file_properties, 0x033F_DD10);
+        assert_eq!(element.title_key, 182_000);
+        assert_eq!((element.part_count, element.part_size), (5, 0x80));
+        let texts: Vec<_> = element.frames.iter()
+            .map(|frame| (frame.tag.clone(), frame.text().unwrap()))
+            .collect();
+        assert_eq!(texts, vec![
+            (b"TIT2".to_vec(), "Pyramid song".to_string()),
+            (b"TPE1".to_vec(), "Radiohead".to_string()),
+            (b"TALB".to_vec(), "Amnesiac".to_string()),
+            (b"TCON".to_vec(), "Rock".to_string()),
+            (b"TSOP".to_vec(), String::new()),
+        ]);
+        assert_eq!(elements[9].text(b"TIT2").unwrap(), "Waste");
+        assert_eq!(elements[9].title_key, 136_000);
+    }
+
+    #[test]
+    fn gtrlst_tags() {
+        let gtrlst = parse_table(include_bytes!("../../tests/fixtures/OMGAUDIO/00GTRLST.DAT")).unwrap();
+        let elements = gtrlst.gtlb().unwrap();
+        let element = |file_ref: u16| elements.iter().find(|element| element.file_ref == file_ref).unwrap();
+        assert!(element(1).tags.is_empty());
+        assert_eq!(element(2).tags, vec![b"TPE1".to_vec()]);
+        assert_eq!(element(0x2D).tags, vec![b"TPE1".to_vec(), b"TALB".to_vec()]);
+        // The trailing bytes are padded with zeros.
+        let trailing: Vec<_> = element(0x2D).trailing.iter().cloned().filter(|&byte| byte != 0).collect();
+        assert_eq!(trailing, b"TRNOTTCCTTCC");
+    }
+
+    #[test]
+    fn title_lists_counts() {
+        let cntinf = parse_table(include_bytes!("../../tests/fixtures/OMGAUDIO/04CNTINF.DAT")).unwrap();
+        let mut cidlst = parse_table(include_bytes!("../../tests/fixtures/OMGAUDIO/05CIDLST.DAT")).unwrap();
+        assert!(check_title_lists(&cntinf, &cidlst).is_ok());
+
+        cidlst.cilb_mut().unwrap().pop();
+        cidlst.relayout();
+        assert_eq!(check_title_lists(&cntinf, &cidlst).unwrap_err(), "04CNTINF lists 10 titles, but 05CIDLST lists 9 titles");
+    }
+
+    #[test]
+    #[should_panic(expected = "no room for its tag and encoding")]
+    fn frame_without_room() {
+        Frame::new(b"TIT2", "", 4);
+    }
+}
@@ -27,3 +27,498 @@ XX	0d	0	0	2	var7	var7	var7	var7	var7	var7	0	"0"	0	"0"	0	"0"		var5 (2 bytes)	Trac
 0c50	0	0	0	0	0	0	0	0	0	0	0	0	0	0	0	0
 0c60	Here starts the audio file... For MP3 the ID3 tags header should be removed...
 */
+
+use super::{Parser, Result};
+use super::text;
+
+/// The offset of the EA3 block, which is also the size of the ea3 tag.
+pub const EA3_BLOCK_OFFSET: usize = 0x0C00;
+pub const HEADER_SIZE: usize = 0x0C60;
+
+pub const MP3_FORMAT: u32 = 0x033FDD10;
+pub const WMA_FORMAT: u32 = 0x0540B345;
+
+pub const NO_PROTECTION: u16 = 0xFFFF;
+
+/// The ids of the ID3v2.2 frames which are read, with the corresponding ids of ID3v2.3.
+const V2_2_FRAMES: &[(&[u8], &[u8])] = &[
+    (b"TT2", b"TIT2"), (b"TP1", b"TPE1"), (b"TP2", b"TPE2"), (b"TAL", b"TALB"), (b"TCO", b"TCON"), (b"TSP", b"TSOP"), (b"TS2", b"TSO2"),
+    (b"TYE", b"TYER"), (b"TLE", b"TLEN"), (b"TRK", b"TRCK"), (b"TXX", b"TXXX"),
+];
+
+/// The header of an OMA file: the ea3 tag, followed by the EA3 block.
+pub struct MusicHeader {
+    pub tag: Tag,
+    pub block: Ea3Block,
+    pub audio_offset: usize,
+}
+
+/// The EA3 block found at 0x0C00.
+pub struct Ea3Block {
+    pub version: u8,
+    pub protection: u16,
+    pub format: u32,
+}
+
+#[derive(Clone, Default)]
+pub struct Tag {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    /// The text to sort the artist by (TSOP, or TSO2 when the album artist TPE2 is the artist or is missing).
+    pub sort_name: Option<String>,
+    pub track_number: Option<u32>,
+    pub year: Option<u16>,
+    /// The length of the title in milliseconds (TLEN), also used as its title_key.
+    pub length: Option<u32>,
+}
+
+pub fn parse_header(buffer: &[u8]) -> Result<MusicHeader> {
+    let mut parser = Parser::new(buffer);
+    parser.music_header()
+}
+
+/// Read the ID3v2.2, ID3v2.3 or ID3v2.4 tag at the beginning of an MP3 file. A file without ID3v2 tag has an empty tag.
+/// The numbers which cannot be read, like a TYER "2003-05-01", are left out.
+pub fn parse_id3(buffer: &[u8]) -> Result<Tag> {
+    if !buffer.starts_with(b"ID3") {
+        return Ok(Tag::default());
+    }
+    let mut parser = Parser::new(buffer);
+    parser.id3()
+}
+
+/// The length in milliseconds of MPEG audio, without its ID3 tags, summing the duration of its frames.
+pub fn mpeg_length(audio: &[u8]) -> Option<u32> {
+    let mut index = 0;
+    let mut microseconds = 0u64;
+    while index + 4 <= audio.len() {
+        match mpeg_frame(&audio[index..index + 4]) {
+            Some((len, samples, sample_rate)) => {
+                microseconds += samples as u64 * 1_000_000 / sample_rate as u64;
+                index += len;
+            },
+            // Skip the garbage between the frames.
+            None => index += 1,
+        }
+    }
+    if microseconds == 0 {
+        None
+    }
+    else {
+        Some((microseconds / 1000) as u32)
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn ea3_block(&mut self) -> Result<(Ea3Block, usize)> {
+        let start = self.index;
+        self.eat(b"EA3")?;
+        let version = self.u8()?;
+        let header_size = self.u16()?;
+        let protection = self.u16()?;
+        self.take(0x18)?;
+        let format = self.u32()?;
+        let block = Ea3Block {
+            version,
+            protection,
+            format,
+        };
+        Ok((block, start + header_size as usize))
+    }
+
+    fn id3(&mut self) -> Result<Tag> {
+        self.eat(b"ID3")?;
+        let version = self.u8()?;
+        let _revision = self.u8()?;
+        let flags = self.u8()?;
+        let size = self.syncsafe_u32()?;
+        if !(2..=4).contains(&version) {
+            return Err(format!("Unsupported ID3 version 2.{}", version));
+        }
+        // The unsynchronisation (a zero inserted after each 0xFF) covers the whole tag before ID3v2.4, and each frame
+        // which has the flag since.
+        let body = self.take(size as usize)?;
+        let unsynchronised = flags & 0x80 != 0;
+        let body =
+            if unsynchronised && version < 4 {
+                resynchronise(body)
+            }
+            else {
+                body.to_vec()
+            };
+        let mut parser = Parser::new(&body);
+        if version == 2 && flags & 0x40 != 0 {
+            return Err("Unsupported compressed ID3v2.2 tag".to_string());
+        }
+        if flags & 0x40 != 0 {
+            // The size of the extended header includes itself in ID3v2.4 only.
+            let len =
+                if version == 4 {
+                    (parser.syncsafe_u32()? as usize).saturating_sub(4)
+                }
+                else {
+                    parser.u32()? as usize
+                };
+            parser.take(len)?;
+        }
+        parser.tag(version, unsynchronised && version == 4)
+    }
+
+    fn music_header(&mut self) -> Result<MusicHeader> {
+        let magic = self.take(3)?;
+        if !magic.eq_ignore_ascii_case(b"ea3") {
+            return Err(format!("Expected an ea3 tag, actual bytes {:?}", magic));
+        }
+        let _version = self.u16()?;
+        let _flags = self.u8()?;
+        let size = self.syncsafe_u32()?;
+        let tag = Parser::new(self.take(size as usize)?).tag(3, false)?;
+        let (block, audio_offset) = self.ea3_block()?;
+        if audio_offset > self.buffer.len() {
+            return Err(format!("Audio offset {:x} is past the end of the file", audio_offset));
+        }
+        Ok(MusicHeader {
+            tag,
+            block,
+            audio_offset,
+        })
+    }
+
+    fn u24(&mut self) -> Result<u32> {
+        let bytes = self.take(3)?;
+        Ok((bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32)
+    }
+
+    fn syncsafe_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok((bytes[0] as u32) << 21 | (bytes[1] as u32) << 14 | (bytes[2] as u32) << 7 | bytes[3] as u32)
+    }
+
+    /// The frames of an ID3v2 tag, whose sizes are syncsafe since the version 4.
+    /// The frames of the version 2 have 3-character ids, read as the ids of the version 3, and no flags.
+    /// In the version 4, the frames are unsynchronised when `unsynchronised` is set or when they have the flag.
+    fn tag(&mut self, version: u8, unsynchronised: bool) -> Result<Tag> {
+        let mut tag = Tag::default();
+        let mut album_artist = None;
+        let mut album_artist_sort = None;
+        let header_size = if version == 2 { 6 } else { 10 };
+        while self.buffer.len() - self.index >= header_size {
+            let id =
+                if version == 2 {
+                    let id = self.take(3)?;
+                    V2_2_FRAMES.iter()
+                        .find(|&&(old_id, _)| old_id == id)
+                        .map(|&(_, id)| id)
+                        .unwrap_or(id)
+                }
+                else {
+                    self.take(4)?
+                };
+            if id[0] == 0 {
+                // Filling zeros to reach the end of the header.
+                break;
+            }
+            let size =
+                match version {
+                    2 => self.u24()?,
+                    3 => self.u32()?,
+                    _ => self.syncsafe_u32()?,
+                };
+            let flags =
+                if version > 2 {
+                    self.u16()?
+                }
+                else {
+                    0
+                };
+            let mut data = self.take(size as usize)?.to_vec();
+            if version == 4 {
+                if unsynchronised || flags & 0x0002 != 0 {
+                    data = resynchronise(&data);
+                }
+                if flags & 0x0001 != 0 {
+                    // The data length indicator.
+                    data.drain(..4.min(data.len()));
+                }
+            }
+            let data = &data[..];
+            match id {
+                b"TIT2" => tag.title = Some(text_frame(data)?),
+                b"TPE1" => tag.artist = Some(text_frame(data)?),
+                b"TPE2" => album_artist = Some(text_frame(data)?),
+                b"TALB" => tag.album = Some(text_frame(data)?),
+                b"TCON" => tag.genre = Some(text_frame(data)?),
+                b"TSOP" => tag.sort_name = Some(text_frame(data)?),
+                b"TSO2" => album_artist_sort = Some(text_frame(data)?),
+                b"TYER" => tag.year = number(&text_frame(data)?),
+                b"TLEN" => tag.length = number(&text_frame(data)?),
+                b"TRCK" => {
+                    // The track number may be followed by the number of tracks, like "2/12".
+                    let text = text_frame(data)?;
+                    tag.track_number = number(text.split('/').next().unwrap_or(""));
+                },
+                b"TXXX" => {
+                    let (description, value) = user_text_frame(data)?;
+                    if description == "OMG_TRACK" {
+                        tag.track_number = number(&value);
+                    }
+                },
+                _ => (),
+            }
+        }
+        // TSO2 is the sort name of the album artist, which is only the one of the artist when they are the same.
+        let same_artist = album_artist.iter()
+            .all(|album_artist| album_artist.is_empty() || Some(album_artist) == tag.artist.as_ref());
+        if tag.sort_name.is_none() && same_artist {
+            tag.sort_name = album_artist_sort;
+        }
+        Ok(tag)
+    }
+}
+
+/// The length in bytes, the number of samples and the sample rate of the MPEG audio frame starting with the header.
+fn mpeg_frame(header: &[u8]) -> Option<(usize, u32, u32)> {
+    const BITRATES: [[u32; 14]; 5] = [
+        [32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+        [32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+        [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+        [32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+        [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    // 3: MPEG 1, 2: MPEG 2, 0: MPEG 2.5.
+    let version = (header[1] >> 3) & 3;
+    // 3: layer I, 2: layer II, 1: layer III.
+    let layer = (header[1] >> 1) & 3;
+    let bitrate_index = (header[2] >> 4) as usize;
+    let sample_rate_index = ((header[2] >> 2) & 3) as usize;
+    let padding = ((header[2] >> 1) & 1) as u32;
+    if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+        return None;
+    }
+    let bitrates =
+        match (version, layer) {
+            (3, 3) => &BITRATES[0],
+            (3, 2) => &BITRATES[1],
+            (3, _) => &BITRATES[2],
+            (_, 3) => &BITRATES[3],
+            _ => &BITRATES[4],
+        };
+    let bitrate = bitrates[bitrate_index - 1] * 1000;
+    let sample_rate = SAMPLE_RATES[sample_rate_index] >> (3 - version.max(1));
+    let samples =
+        match layer {
+            3 => 384,
+            1 if version != 3 => 576,
+            _ => 1152,
+        };
+    let len =
+        if layer == 3 {
+            (12 * bitrate / sample_rate + padding) * 4
+        }
+        else {
+            samples / 8 * bitrate / sample_rate + padding
+        };
+    Some((len as usize, samples, sample_rate))
+}
+
+/// Remove the zero inserted after each 0xFF by the unsynchronisation.
+fn resynchronise(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    for (index, &byte) in bytes.iter().enumerate() {
+        if byte != 0 || index == 0 || bytes[index - 1] != 0xFF {
+            result.push(byte);
+        }
+    }
+    result
+}
+
+/// The number of a text frame, or None for a text like "2003-05-01", "A1" or "".
+fn number<N: ::std::str::FromStr>(string: &str) -> Option<N> {
+    string.trim().parse().ok()
+}
+
+fn text_frame(data: &[u8]) -> Result<String> {
+    match data.split_first() {
+        Some((&encoding, bytes)) => text::decode(encoding as u16, bytes),
+        None => Ok(String::new()),
+    }
+}
+
+/// Split a TXXX frame into its description and its value.
+fn user_text_frame(data: &[u8]) -> Result<(String, String)> {
+    let (&encoding, bytes) = data.split_first()
+        .ok_or_else(|| "Empty TXXX frame".to_string())?;
+    let (description, value) = text::split_terminated(encoding as u16, bytes);
+    Ok((text::decode(encoding as u16, description)?, text::decode(encoding as u16, value)?))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{mpeg_length, parse_id3};
+
+    /// An ID3v2 tag of the version, with the frames.
+    fn id3(version: u8, frames: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut body = vec![];
+        for &(id, data) in frames {
+            let size = data.len() as u32;
+            body.extend_from_slice(id);
+            match version {
+                2 => body.extend_from_slice(&[(size >> 16) as u8, (size >> 8) as u8, size as u8]),
+                3 => body.extend_from_slice(&[(size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8, size as u8]),
+                _ => body.extend_from_slice(&[0, 0, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]),
+            }
+            if version > 2 {
+                body.extend_from_slice(&[0, 0]);
+            }
+            body.extend_from_slice(data);
+        }
+        let size = body.len() as u32;
+        let mut tag = vec![b'I', b'D', b'3', version, 0, 0];
+        tag.extend_from_slice(&[0, 0, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]);
+        tag.extend(body);
+        tag
+    }
+
+    /// Insert a zero after each 0xFF.
+    fn unsynchronise(bytes: &[u8]) -> Vec<u8> {
+        let mut result = vec![];
+        for &byte in bytes {
+            result.push(byte);
+            if byte == 0xFF {
+                result.push(0);
+            }
+        }
+        result
+    }
+
+    /// MPEG 1 layer III frames of 128 kbit/s at 44100 Hz: 417 bytes and 1152 samples each.
+    pub fn mpeg_frames(count: usize) -> Vec<u8> {
+        let mut audio = vec![];
+        for _ in 0..count {
+            let start = audio.len();
+            audio.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+            audio.resize(start + 417, 0);
+        }
+        audio
+    }
+
+    #[test]
+    fn parse_id3_v2_3() {
+        let tag = parse_id3(&id3(3, &[
+            (b"TIT2", b"\x00Waste"),
+            (b"TPE1", b"\x01\xFF\xFEA\x00r\x00c\x00h\x00i\x00v\x00e\x00"),
+            (b"TALB", b"\x00Noise"),
+            (b"TRCK", b"\x003/12"),
+            (b"TYER", b"\x002004"),
+            (b"TLEN", b"\x00271000"),
+        ])).unwrap();
+        assert_eq!(tag.title.as_ref().unwrap(), "Waste");
+        assert_eq!(tag.artist.as_ref().unwrap(), "Archive");
+        assert_eq!(tag.album.as_ref().unwrap(), "Noise");
+        assert_eq!(tag.track_number, Some(3));
+        assert_eq!(tag.year, Some(2004));
+        assert_eq!(tag.length, Some(271000));
+    }
+
+    #[test]
+    fn parse_id3_v2_4_unreadable_numbers() {
+        let tag = parse_id3(&id3(4, &[
+            (b"TIT2", b"\x03Syst\xC3\xA8me"),
+            (b"TRCK", b"\x00A1"),
+            (b"TYER", b"\x002003-05-01"),
+            (b"TLEN", b"\x00"),
+        ])).unwrap();
+        assert_eq!(tag.title.as_ref().unwrap(), "Système");
+        assert_eq!(tag.track_number, None);
+        assert_eq!(tag.year, None);
+        assert_eq!(tag.length, None);
+    }
+
+    #[test]
+    fn parse_id3_v2_2() {
+        let tag = parse_id3(&id3(2, &[
+            (b"TT2", b"\x00Knives out"),
+            (b"TP1", b"\x00Radiohead"),
+            (b"TAL", b"\x00Amnesiac"),
+            (b"TRK", b"\x006"),
+            (b"TSP", b"\x00Radiohead"),
+        ])).unwrap();
+        assert_eq!(tag.title.as_ref().unwrap(), "Knives out");
+        assert_eq!(tag.artist.as_ref().unwrap(), "Radiohead");
+        assert_eq!(tag.album.as_ref().unwrap(), "Amnesiac");
+        assert_eq!(tag.track_number, Some(6));
+        assert_eq!(tag.sort_name.as_ref().unwrap(), "Radiohead");
+    }
+
+    #[test]
+    fn album_artist_sort_name() {
+        let sort_name = |frames: &[(&[u8], &[u8])]| parse_id3(&id3(3, frames)).unwrap().sort_name;
+        assert_eq!(sort_name(&[(b"TSO2", b"\x00Beatles, The"), (b"TPE1", b"\x00The Beatles")]).unwrap(), "Beatles, The");
+        assert_eq!(sort_name(&[
+            (b"TPE1", b"\x00The Beatles"),
+            (b"TPE2", b"\x00The Beatles"),
+            (b"TSO2", b"\x00Beatles, The"),
+        ]).unwrap(), "Beatles, The");
+        assert_eq!(sort_name(&[(b"TPE1", b"\x00Ringo"), (b"TPE2", b"\x00"), (b"TSO2", b"\x00Beatles, The")]).unwrap(),
+            "Beatles, The");
+        assert!(sort_name(&[
+            (b"TPE1", b"\x00Ringo Starr"),
+            (b"TSO2", b"\x00Beatles, The"),
+            (b"TPE2", b"\x00The Beatles"),
+        ]).is_none());
+        assert_eq!(sort_name(&[
+            (b"TPE1", b"\x00Ringo Starr"),
+            (b"TSO2", b"\x00Beatles, The"),
+            (b"TSOP", b"\x00Starr, Ringo"),
+        ]).unwrap(), "Starr, Ringo");
+
+        let tag = parse_id3(&id3(2, &[(b"TP1", b"\x00Ringo Starr"), (b"TP2", b"\x00The Beatles"), (b"TS2", b"\x00Beatles")]));
+        assert!(tag.unwrap().sort_name.is_none());
+    }
+
+    #[test]
+    fn parse_unsynchronised_id3() {
+        // "Waste" in UTF-16 little endian, whose byte order mark starts with 0xFF.
+        let title: &[u8] = b"\x01\xFF\xFEW\x00a\x00s\x00t\x00e\x00";
+
+        // The whole tag is unsynchronised in ID3v2.3.
+        let tag = id3(3, &[(b"TIT2", title), (b"TPE1", b"\x00Archive")]);
+        let body = unsynchronise(&tag[10..]);
+        let size = body.len();
+        let mut unsynchronised = tag[..6].to_vec();
+        unsynchronised[5] = 0x80;
+        unsynchronised.extend_from_slice(&[0, 0, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]);
+        unsynchronised.extend(body);
+        let tag = parse_id3(&unsynchronised).unwrap();
+        assert_eq!(tag.title.as_ref().unwrap(), "Waste");
+        assert_eq!(tag.artist.as_ref().unwrap(), "Archive");
+
+        // Only the frames with the flag are unsynchronised in ID3v2.4.
+        let mut tag = id3(4, &[(b"TIT2", &unsynchronise(title)), (b"TPE1", b"\x00Archive")]);
+        tag[19] = 0x02;
+        let tag = parse_id3(&tag).unwrap();
+        assert_eq!(tag.title.as_ref().unwrap(), "Waste");
+        assert_eq!(tag.artist.as_ref().unwrap(), "Archive");
+    }
+
+    #[test]
+    fn parse_without_id3() {
+        assert!(parse_id3(&mpeg_frames(1)).unwrap().title.is_none());
+    }
+
+    #[test]
+    fn mpeg_frames_length() {
+        // 1152 / 44100 s per frame: 26.122 ms.
+        assert_eq!(mpeg_length(&mpeg_frames(100)), Some(2612));
+        let mut audio = vec![0; 10];
+        audio.extend(mpeg_frames(10));
+        assert_eq!(mpeg_length(&audio), Some(261));
+        assert_eq!(mpeg_length(&[0; 1000]), None);
+    }
+}
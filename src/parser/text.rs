@@ -0,0 +1,90 @@
+/*
+
+# Text encoding
+
+Every name in the tables is stored in a frame: a tag, a 2 bytes encoding marker and a fixed
+slot (122 bytes for the frames of 0x80 bytes).
+The encoding marker follows the ID3v2 convention:
+0x0000	ISO-8859-1
+0x0001	UTF-16 with a byte order mark
+0x0002	UTF-16 big endian	This is the value written by SonicStage
+0x0003	UTF-8
+The text is padded with zeros up to the end of the slot.
+
+*/
+
+use std::char;
+use std::result;
+
+use super::Result;
+
+pub const ISO_8859_1: u16 = 0;
+pub const UTF16_BOM: u16 = 1;
+pub const UTF16_BE: u16 = 2;
+pub const UTF8: u16 = 3;
+
+/// Decode the content of a text slot, stopping at the first null character.
+pub fn decode(encoding: u16, bytes: &[u8]) -> Result<String> {
+    match encoding {
+        ISO_8859_1 => Ok(bytes.iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect()),
+        UTF16_BOM => {
+            match bytes.get(..2) {
+                Some([0xFF, 0xFE]) => decode_utf16(&bytes[2..], false),
+                Some([0xFE, 0xFF]) => decode_utf16(&bytes[2..], true),
+                _ => decode_utf16(bytes, true),
+            }
+        },
+        UTF16_BE => decode_utf16(bytes, true),
+        UTF8 => {
+            let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+            String::from_utf8(bytes[..len].to_vec())
+                .map_err(|err| err.to_string())
+        },
+        _ => Err(format!("Unknown text encoding {:x}", encoding)),
+    }
+}
+
+/// Split the bytes after the first null character: the text before, the remaining bytes after.
+pub fn split_terminated(encoding: u16, bytes: &[u8]) -> (&[u8], &[u8]) {
+    let (len, terminator_len) =
+        if encoding == UTF16_BOM || encoding == UTF16_BE {
+            (bytes.chunks(2).position(|chunk| chunk == [0, 0]).map(|position| position * 2), 2)
+        }
+        else {
+            (bytes.iter().position(|&byte| byte == 0), 1)
+        };
+    match len {
+        Some(len) => (&bytes[..len], &bytes[len + terminator_len..]),
+        None => (bytes, &[]),
+    }
+}
+
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> Result<String> {
+    let units = bytes.chunks(2)
+        .filter(|chunk| chunk.len() == 2)
+        .map(|chunk| {
+            if big_endian {
+                (chunk[0] as u16) << 8 | chunk[1] as u16
+            }
+            else {
+                (chunk[1] as u16) << 8 | chunk[0] as u16
+            }
+        })
+        .take_while(|&unit| unit != 0);
+    char::decode_utf16(units)
+        .collect::<result::Result<String, _>>()
+        .map_err(|err| err.to_string())
+}
+
+/// Encode the text in UTF-16 big endian, without padding.
+pub fn encode_utf16(text: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    for unit in text.encode_utf16() {
+        bytes.push((unit >> 8) as u8);
+        bytes.push(unit as u8);
+    }
+    bytes
+}
@@ -0,0 +1,595 @@
+/*
+
+# Repair
+
+Only 04CNTINF, 05CIDLST and the files in 10F0X describe the titles, the other tables are derived from them.
+A title is valid when its file exists in 10F0X: the views are rebuilt from the valid titles, keeping their title_id.
+- 01TREE01 and 03GINF01: an item per run of titles of the same album, in upload order.
+- 01TREEXX and 03GINFXX keyed on one tag: an item per artist, album or genre in order of appearance, the groups in
+  the order of the sort module.
+- 01TREE2D and 03GINF2D: the blank item, then each artist followed by its albums, the groups in the order of the sort
+  module.
+- The keys of 03GINFXX and the global_key of 02TREINF.
+The other views, like 22, are left untouched.
+
+When adding or deleting titles, the existing items and the upload order of 01TREE01 are kept (see `Change`).
+When repairing, the upload history is lost: the titles are uploaded in title_id order. The frames of an item which are
+not rebuilt (like TSOP or PICP) are kept when the same item already existed.
+
+A missing 10F0X folder, or the folder of another device, would make every title invalid and empty the views. So
+repairing is refused, unless it is forced, when no title of 04CNTINF has a file or when more than
+MAX_DROPPED_PERCENT % of the titles of the views would be dropped.
+
+*/
+
+use std::collections::BTreeMap;
+use std::mem;
+
+use collation::Collation;
+use database::{OmaDatabase, UPLOADED_VIEW};
+use keys::{global_key, group_keys};
+use parser::{Association, CilbElement, FRAME_SIZE, Frame, GpfbElement, GplbElement, Result, TitleId, TplbElement};
+use parser::music::{HEADER_SIZE, parse_header};
+use sort::{Track, artist_album_order, tag_order};
+use storage::Storage;
+use tree::{Group, artist_albums, groups};
+
+/// The share of the titles of the views, in percent, which repairing drops without being forced.
+const MAX_DROPPED_PERCENT: usize = 25;
+
+const ARTIST_ALBUM_FRAMES: &[&[u8]] = &[b"TIT2", b"XSOT"];
+const GROUP_FRAMES: &[&[u8]] = &[b"TIT2"];
+const UPLOADED_FRAMES: &[&[u8]] = &[b"TIT2", b"TPE1", b"TCON", b"TSOP", b"PICP", b"PIC0"];
+
+/// Why the views are rebuilt, which tells what happens to the items of 03GINFXX.
+#[derive(Clone, Copy)]
+pub enum Change<'a> {
+    /// The titles were added: they are uploaded at the end of 01TREE01, in this order.
+    /// Every item is kept and the new ones are added at the end: the unused items are at the tail of GPLB.
+    Added(&'a [TitleId]),
+    /// The titles are deleted: every item is kept, the empty groups going to the unused tail of GPLB. The unused
+    /// items of 03GINF01 are blank elements, which take the next uploaded albums.
+    Removed(&'a [TitleId]),
+    /// The items are rebuilt from 04CNTINF only.
+    Repaired,
+    /// The lists are sorted again: every item is kept, like when adding no title.
+    Sorted,
+}
+
+impl<'a> Change<'a> {
+    fn added(self) -> &'a [TitleId] {
+        match self {
+            Change::Added(title_ids) => title_ids,
+            _ => &[],
+        }
+    }
+
+    fn is_repair(self) -> bool {
+        matches!(self, Change::Repaired)
+    }
+
+    fn removed(self) -> &'a [TitleId] {
+        match self {
+            Change::Removed(title_ids) => title_ids,
+            _ => &[],
+        }
+    }
+}
+
+/// The rebuilt content of 01TREEXX and 03GINFXX, with the key identifying each item (like the name of an artist).
+struct View<K> {
+    frames: &'static [&'static [u8]],
+    elements: Vec<GpfbElement>,
+    /// None when the item cannot be matched, like the blank item of 03GINF2D.
+    keys: Vec<Option<K>>,
+    /// The elements before rebuilding the items, whose frames are kept by the new items.
+    previous: Vec<GpfbElement>,
+    gplb: Vec<GplbElement>,
+    tplb: Vec<TitleId>,
+    /// Whether the class header of GPLB repeats the number of used groups instead of the number of groups.
+    count_used_groups: bool,
+}
+
+impl<K: PartialEq> View<K> {
+    /// A view whose items are rebuilt.
+    fn new(frames: &'static [&'static [u8]], previous: Vec<GpfbElement>) -> Self {
+        Self {
+            frames,
+            elements: vec![],
+            keys: vec![],
+            previous,
+            gplb: vec![],
+            tplb: vec![],
+            count_used_groups: false,
+        }
+    }
+
+    /// A view keeping the existing items.
+    fn with_items(frames: &'static [&'static [u8]], elements: Vec<GpfbElement>, keys: Vec<Option<K>>) -> Self {
+        Self {
+            elements,
+            keys,
+            ..Self::new(frames, vec![])
+        }
+    }
+
+    /// Start a group of the item at the next title.
+    fn group(&mut self, item_id: u16, association: Association) {
+        self.gplb.push(GplbElement {
+            id: item_id,
+            association,
+            title_id: self.tplb.len() as u16 + 1,
+            reserved: vec![0; 2],
+        });
+    }
+
+    /// The item_id of the item, which is added at the end of 03GINFXX if it does not exist.
+    fn item_id(&mut self, key: K, texts: &[String]) -> u16 {
+        if let Some(index) = self.keys.iter().position(|item| item.as_ref() == Some(&key)) {
+            return index as u16 + 1;
+        }
+        let element = item(self.frames, texts, &self.previous);
+        self.elements.push(element);
+        self.keys.push(Some(key));
+        self.elements.len() as u16
+    }
+
+    /// List the items without groups at the tail of GPLB.
+    fn finish(&mut self) {
+        for item_id in 1..=self.elements.len() as u16 {
+            if self.gplb.iter().all(|element| element.id != item_id) {
+                self.gplb.push(GplbElement {
+                    id: item_id,
+                    association: Association::Unused,
+                    title_id: 0,
+                    reserved: vec![0; 2],
+                });
+            }
+        }
+    }
+}
+
+/// Rebuild the views, the keys and 05CIDLST from 04CNTINF and the files in 10F0X.
+/// Unless `force` is set, fail when too many titles would be dropped (see "Repair").
+/// The tables are changed in memory: call `save` to write them.
+pub fn repair(database: &mut OmaDatabase, force: bool) -> Result<()> {
+    if !force {
+        check_dropped_titles(database)?;
+    }
+    repair_cidlst(database)?;
+    rebuild_views(database, Change::Repaired)
+}
+
+/// Rebuild the views and the keys from the valid titles.
+pub fn rebuild_views(database: &mut OmaDatabase, change: Change) -> Result<()> {
+    let tracks: Vec<_> = tracks(database).into_iter()
+        .filter(|track| !change.removed().contains(&track.id))
+        .collect();
+    let collation = database.collation.clone();
+    let views: Vec<_> = database.gtrlst.gtlb()?.iter()
+        .map(|element| (element.file_ref as u8, element.tags.clone()))
+        .collect();
+    let mut rebuilt = vec![];
+    for (view, tags) in views {
+        if !database.trees.contains_key(&view) || !database.ginfs.contains_key(&view) {
+            continue;
+        }
+        match tags.len() {
+            0 if view == UPLOADED_VIEW => {
+                let groups =
+                    if change.is_repair() {
+                        None
+                    }
+                    else {
+                        Some(groups(&database.trees[&view])?)
+                    };
+                let previous = take_items(database, view)?;
+                let mut content = uploaded(&tracks, previous, groups, change.added())?;
+                content.finish();
+                write_view(database, view, content)?;
+            },
+            1 if is_group_tag(&tags[0]) => {
+                let previous = take_items(database, view)?;
+                let mut content =
+                    if change.is_repair() {
+                        View::new(GROUP_FRAMES, previous)
+                    }
+                    else {
+                        let keys = previous.iter()
+                            .map(|element| Some(element.text(b"TIT2").unwrap_or_default()))
+                            .collect();
+                        View::with_items(GROUP_FRAMES, previous, keys)
+                    };
+                content.count_used_groups = tags[0] == b"TPE1";
+                by_tag(&collation, &tracks, &tags[0], &mut content);
+                content.finish();
+                write_view(database, view, content)?;
+            },
+            2 if tags[0] == b"TPE1" && tags[1] == b"TALB" => {
+                let artists =
+                    if change.is_repair() {
+                        vec![]
+                    }
+                    else {
+                        artist_albums(&database.trees[&view])?
+                    };
+                let previous = take_items(database, view)?;
+                let mut content =
+                    if change.is_repair() {
+                        View::new(ARTIST_ALBUM_FRAMES, previous)
+                    }
+                    else {
+                        let mut keys: Vec<_> = previous.iter().map(|_| None).collect();
+                        for artist in &artists {
+                            let name = item_name(&previous, artist.ginf_item);
+                            set_key(&mut keys, artist.ginf_item, (name.clone(), None));
+                            for album in &artist.albums {
+                                set_key(&mut keys, album.ginf_item, (name.clone(), Some(item_name(&previous, album.ginf_item))));
+                            }
+                        }
+                        View::with_items(ARTIST_ALBUM_FRAMES, previous, keys)
+                    };
+                artist_album_groups(&collation, &tracks, &mut content);
+                content.finish();
+                write_view(database, view, content)?;
+            },
+            _ => continue,
+        }
+        rebuilt.push(view);
+    }
+    database.link()?;
+    update_keys(database, &rebuilt)
+}
+
+/// The view 2D: the blank item, then each artist followed by its albums, in order of appearance.
+fn artist_album_groups(collation: &Collation, tracks: &[Track], view: &mut View<(String, Option<String>)>) {
+    if view.elements.is_empty() {
+        view.elements.push(item(view.frames, &[String::new(), String::new()], &[]));
+        view.keys.push(None);
+    }
+    for track in tracks {
+        view.item_id((track.artist.clone(), None), &[track.artist.clone(), track.artist.clone()]);
+        view.item_id((track.artist.clone(), Some(track.album.clone())), &[track.album.clone(), track.album.clone()]);
+    }
+
+    let mut sorted: Vec<_> = tracks.iter().collect();
+    sorted.sort_by(|track1, track2| artist_album_order(collation, track1, track2));
+    view.group(1, Association::Used);
+    let mut previous: Option<&Track> = None;
+    for track in sorted {
+        if previous.map(|previous| &previous.artist) != Some(&track.artist) {
+            let item_id = view.item_id((track.artist.clone(), None), &[]);
+            view.group(item_id, Association::Used);
+        }
+        if previous.map(|previous| (&previous.artist, &previous.album)) != Some((&track.artist, &track.album)) {
+            let item_id = view.item_id((track.artist.clone(), Some(track.album.clone())), &[]);
+            view.group(item_id, Association::Album);
+        }
+        view.tplb.push(track.id);
+        previous = Some(track);
+    }
+}
+
+/// A view keyed on one tag: an item per value in order of appearance.
+fn by_tag(collation: &Collation, tracks: &[Track], tag: &[u8], view: &mut View<String>) {
+    for track in tracks {
+        let name = track.value(tag).to_string();
+        view.item_id(name.clone(), &[name]);
+    }
+
+    let mut sorted: Vec<_> = tracks.iter().collect();
+    sorted.sort_by(|track1, track2| tag_order(collation, tag, track1, track2));
+    let mut previous: Option<&str> = None;
+    for track in sorted {
+        let name = track.value(tag);
+        if previous != Some(name) {
+            let item_id = view.item_id(name.to_string(), &[]);
+            view.group(item_id, Association::Used);
+        }
+        view.tplb.push(track.id);
+        previous = Some(name);
+    }
+}
+
+fn is_group_tag(tag: &[u8]) -> bool {
+    tag == b"TALB" || tag == b"TCON" || tag == b"TPE1"
+}
+
+/// An element of 03GINFXX with the texts in its frames.
+/// The frames without text are taken from the previous element with the same texts, if any.
+fn item(frame_tags: &[&[u8]], texts: &[String], previous: &[GpfbElement]) -> GpfbElement {
+    let previous = previous.iter()
+        .find(|element| is_same_item(element, frame_tags, texts));
+    let frames: Vec<_> = frame_tags.iter().zip(texts)
+        .map(|(tag, text)| {
+            previous.filter(|_| text.is_empty())
+                .and_then(|element| element.frame(tag))
+                .cloned()
+                .unwrap_or_else(|| Frame::new(tag, text, FRAME_SIZE))
+        })
+        .collect();
+    GpfbElement {
+        magic_key: previous.map(|element| element.magic_key.clone())
+            .unwrap_or_else(|| vec![0; 8]),
+        key: 0,
+        part_count: frames.len() as u16,
+        part_size: FRAME_SIZE,
+        frames,
+        trailing: vec![],
+    }
+}
+
+fn item_name(elements: &[GpfbElement], item_id: u16) -> String {
+    (item_id as usize).checked_sub(1)
+        .and_then(|index| elements.get(index))
+        .and_then(|element| element.text(b"TIT2").ok())
+        .unwrap_or_default()
+}
+
+fn is_same_item(element: &GpfbElement, frame_tags: &[&[u8]], texts: &[String]) -> bool {
+    element.part_size == FRAME_SIZE && texts.iter().any(|text| !text.is_empty()) &&
+        frame_tags.iter().zip(texts)
+            .filter(|&(_, text)| !text.is_empty())
+            .all(|(tag, text)| element.text(tag).ok().as_ref() == Some(text))
+}
+
+/// 05CIDLST lists as many titles as 04CNTINF: the missing titles get a blank drmed_oma_key.
+fn repair_cidlst(database: &mut OmaDatabase) -> Result<()> {
+    let count = database.cntinf.cnfb()?.len();
+    if database.cidlst.cilb()?.len() == count {
+        return Ok(());
+    }
+    {
+        let elements = database.cidlst.cilb_mut()?;
+        elements.truncate(count);
+        while elements.len() < count {
+            elements.push(CilbElement {
+                title_id: elements.len() as TitleId + 1,
+                drmed_oma_key: vec![0; 0x18],
+                trailing: vec![],
+            });
+        }
+    }
+    database.cidlst.relayout();
+    Ok(())
+}
+
+fn set_key<K>(keys: &mut [Option<K>], item_id: u16, key: K) {
+    if let Some(item) = (item_id as usize).checked_sub(1).and_then(|index| keys.get_mut(index)) {
+        *item = Some(key);
+    }
+}
+
+/// Fail when no title of 04CNTINF has a file, or when too many titles of the views have none.
+fn check_dropped_titles(database: &OmaDatabase) -> Result<()> {
+    let titles = database.titles();
+    if !titles.is_empty() && titles.iter().all(|title| !database.storage.has_title(title.id)) {
+        return Err(format!("No title of 04CNTINF has a file in the 10F0X folders of {}: repairing would empty the views, unless forced",
+            database.path.display()));
+    }
+    let listed: Vec<_> = titles.iter()
+        .filter(|title| !title.positions.is_empty())
+        .collect();
+    let dropped = listed.iter()
+        .filter(|title| !database.storage.has_title(title.id))
+        .count();
+    if dropped * 100 > listed.len() * MAX_DROPPED_PERCENT {
+        return Err(format!("{} of the {} titles of the views have no file in 10F0X: repairing would drop them, unless forced",
+            dropped, listed.len()));
+    }
+    Ok(())
+}
+
+/// Remove the elements of 03GINFXX, to rebuild them.
+fn take_items(database: &mut OmaDatabase, view: u8) -> Result<Vec<GpfbElement>> {
+    let ginf = database.ginfs.get_mut(&view)
+        .ok_or_else(|| format!("No 03GINF{:02X}", view))?;
+    Ok(mem::take(ginf.gpfb_mut()?))
+}
+
+/// The track number written in the header of the file of the title, or 0 when it cannot be read.
+fn track_number(storage: &Storage, title_id: TitleId) -> u32 {
+    storage.read_title(title_id, HEADER_SIZE).ok()
+        .and_then(|buffer| parse_header(&buffer).ok())
+        .and_then(|header| header.tag.track_number)
+        .unwrap_or(0)
+}
+
+/// The titles of 04CNTINF whose file exists in 10F0X, in title_id order.
+/// The titles of an artist share the first TSOP frame found, so that they are sorted together.
+fn tracks(database: &OmaDatabase) -> Vec<Track> {
+    let mut tracks: Vec<_> = database.titles().iter()
+        .filter(|title| database.storage.has_title(title.id))
+        .map(|title| Track {
+            id: title.id,
+            name: title.name.clone(),
+            artist: title.artist.clone(),
+            artist_sort: title.sort_name.clone(),
+            album: title.album.clone(),
+            genre: title.genre.clone(),
+            track_number: track_number(&database.storage, title.id),
+        })
+        .collect();
+    let mut sort_names = BTreeMap::new();
+    for track in tracks.iter().filter(|track| !track.artist_sort.is_empty()) {
+        sort_names.entry(track.artist.clone()).or_insert_with(|| track.artist_sort.clone());
+    }
+    for track in &mut tracks {
+        if let Some(sort_name) = sort_names.get(&track.artist) {
+            track.artist_sort.clone_from(sort_name);
+        }
+    }
+    tracks
+}
+
+/// Write the sums of title_keys in the views of 03GINFXX and in 02TREINF.
+pub fn update_keys(database: &mut OmaDatabase, views: &[u8]) -> Result<()> {
+    for &view in views {
+        let keys = group_keys(database, view)?;
+        let ginf = database.ginfs.get_mut(&view)
+            .ok_or_else(|| format!("No 03GINF{:02X}", view))?;
+        for (element, key) in ginf.gpfb_mut()?.iter_mut().zip(keys) {
+            element.key = key;
+        }
+    }
+    let global_key = global_key(database);
+    let mut views_with_titles = vec![];
+    for (&view, tree) in &database.trees {
+        if !tree.tplb()?.is_empty() {
+            views_with_titles.push(view);
+        }
+    }
+    for element in database.treinf.gtfb_mut()? {
+        if element.exists() && views_with_titles.contains(&element.view) {
+            element.global_key = global_key;
+        }
+    }
+    Ok(())
+}
+
+/// The view 01: the groups of `groups` keep their item and their titles which are still valid.
+/// The other titles are uploaded after them: an item per run of titles of the same album, which takes the place of
+/// a blank element of 03GINF01 if any. Without `groups`, the items are rebuilt and the titles uploaded in title_id order.
+/// The TSOP frame of a new item is the sort name of its artist.
+fn uploaded(tracks: &[Track], previous: Vec<GpfbElement>, groups: Option<Vec<Group>>, added: &[TitleId])
+    -> Result<View<()>> {
+    let mut view;
+    let mut uploaded = vec![];
+    match groups {
+        Some(groups) => {
+            let item_count = previous.len();
+            view = View::with_items(UPLOADED_FRAMES, previous, vec![]);
+            for group in groups.iter().filter(|group| group.used) {
+                if group.ginf_item == 0 || group.ginf_item as usize > item_count {
+                    continue;
+                }
+                let titles: Vec<_> = group.titles.iter()
+                    .cloned()
+                    .filter(|title_id| tracks.iter().any(|track| track.id == *title_id) && !added.contains(title_id) &&
+                        !uploaded.contains(title_id))
+                    .collect();
+                if !titles.is_empty() {
+                    view.group(group.ginf_item, Association::Used);
+                    view.tplb.extend_from_slice(&titles);
+                    uploaded.extend(titles);
+                }
+            }
+        },
+        None => view = View::new(UPLOADED_FRAMES, previous),
+    }
+
+    let mut remaining: Vec<_> = tracks.iter()
+        .filter(|track| !uploaded.contains(&track.id))
+        .collect();
+    remaining.sort_by_key(|track| (added.iter().position(|&title_id| title_id == track.id).unwrap_or(added.len()), track.id));
+    let mut previous_texts = None;
+    for track in remaining {
+        let texts = vec![track.album.clone(), track.artist.clone(), track.genre.clone(), String::new(), String::new(),
+            String::new()];
+        if previous_texts.as_ref() != Some(&texts) {
+            let mut element = item(UPLOADED_FRAMES, &texts, &view.previous);
+            if !track.artist_sort.is_empty() {
+                element.set_text(b"TSOP", &track.artist_sort)?;
+            }
+            let blank = (1..=view.elements.len() as u16)
+                .find(|&item_id| view.gplb.iter().all(|element| element.id != item_id));
+            let item_id =
+                match blank {
+                    Some(item_id) => {
+                        view.elements[item_id as usize - 1] = element;
+                        item_id
+                    },
+                    None => {
+                        view.elements.push(element);
+                        view.elements.len() as u16
+                    },
+                };
+            view.group(item_id, Association::Used);
+            previous_texts = Some(texts);
+        }
+        view.tplb.push(track.id);
+    }
+    Ok(view)
+}
+
+/// Replace 01TREEXX and 03GINFXX with the rebuilt view.
+fn write_view<K>(database: &mut OmaDatabase, view: u8, content: View<K>) -> Result<()> {
+    {
+        let ginf = database.ginfs.get_mut(&view)
+            .ok_or_else(|| format!("No 03GINF{:02X}", view))?;
+        *ginf.gpfb_mut()? = content.elements;
+        ginf.class_mut(b"GPFB")?.element_length = 0x10 + content.frames.len() as u16 * FRAME_SIZE;
+        ginf.relayout();
+    }
+
+    let tree = database.trees.get_mut(&view)
+        .ok_or_else(|| format!("No 01TREE{:02X}", view))?;
+    let group_count =
+        if content.count_used_groups {
+            content.gplb.iter().filter(|element| element.association.is_used()).count()
+        }
+        else {
+            content.gplb.len()
+        };
+    let title_count = content.tplb.len() as u16;
+    *tree.gplb_mut()? = content.gplb;
+    *tree.tplb_mut()? = content.tplb.into_iter()
+        .map(|title_id| TplbElement {
+            title_id,
+        })
+        .collect();
+    tree.class_mut(b"GPLB")?.set_repeated_count(group_count as u16);
+    tree.class_mut(b"TPLB")?.set_repeated_count(title_count);
+    tree.relayout();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use database::OmaDatabase;
+    use database::tests::{FIXTURE_TRACKS, fixtures_copy};
+    use menu::render;
+    use verify::verify;
+    use super::repair;
+
+    #[test]
+    fn repair_scrambled_tree() {
+        let path = fixtures_copy("repair", FIXTURE_TRACKS);
+        let mut database = OmaDatabase::open(&path).unwrap();
+        {
+            let tree = database.trees.get_mut(&0x2D).unwrap();
+            tree.tplb_mut().unwrap().reverse();
+            for element in tree.gplb_mut().unwrap() {
+                element.title_id = 1;
+            }
+        }
+        database.ginfs.get_mut(&3).unwrap().gpfb_mut().unwrap()[0].key = 0;
+        assert!(!verify(&database).unwrap().is_empty());
+        assert_ne!(render(&database).unwrap(), include_str!("../tests/fixtures/menu.txt"));
+
+        repair(&mut database, false).unwrap();
+        database.save().unwrap();
+        let database = OmaDatabase::open(&path).unwrap();
+        assert!(verify(&database).unwrap().is_empty());
+        assert_eq!(render(&database).unwrap(), include_str!("../tests/fixtures/menu.txt"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn refuse_dropping_titles() {
+        let path = fixtures_copy("repair-refuse", &[]);
+        let mut database = OmaDatabase::open(&path).unwrap();
+        assert!(repair(&mut database, false).unwrap_err().contains("repairing would empty the views"));
+
+        // 3 of the 9 titles of the views.
+        let path = fixtures_copy("repair-refuse", &FIXTURE_TRACKS[..6]);
+        let mut database = OmaDatabase::open(&path).unwrap();
+        assert_eq!(repair(&mut database, false).unwrap_err(),
+            "3 of the 9 titles of the views have no file in 10F0X: repairing would drop them, unless forced");
+        repair(&mut database, true).unwrap();
+        assert_eq!(database.trees[&1].tplb().unwrap().len(), 6);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+/*
+
+# Sorting the views
+
+The "Adding titles" section of each 01TREEXX gives the order of its lists:
+- 01TREE02 (TPE1): the artists in alphabetical order, the titles of an artist in title name order,
+- 01TREE03 (TALB): the albums in alphabetical order, the titles of an album in track number order,
+- 01TREE04 (TCON): the genres in alphabetical order, the titles of a genre in title name order,
+- 01TREE2D: the artists in alphabetical order, then their albums in alphabetical order, then the titles of an album in
+  track number order.
+The alphabetical order is the one of the Collation of the database, the artists being sorted by their TSOP frame.
+Equal titles are in title name order, then in title_id order, so that the lists are always the same.
+
+GPLB lists the used groups first, in the order of their titles in TPLB, and the unused groups at the tail.
+Sorting rebuilds GPLB and TPLB of these views from 04CNTINF, keeping every item of 03GINFXX: see `Change::Sorted`.
+
+The sort name of an artist is the TSOP frame of its titles in 04CNTINF and of its albums in 03GINF01. The artists of
+03GINF02 only have a TIT2 frame, so they have no sort name: the view is sorted from the titles.
+It is imported from the ID3 tag, and can be set afterwards, like "Beatles" for "The Beatles" or "Rita Mitsouko" for
+"Les Rita Mitsouko".
+
+*/
+
+use std::cmp::Ordering;
+
+use collation::Collation;
+use database::{OmaDatabase, UPLOADED_VIEW};
+use parser::{Result, TitleId};
+use repair::{Change, rebuild_views};
+
+/// A title of the views, with the metadata its place depends on.
+pub struct Track {
+    pub id: TitleId,
+    pub name: String,
+    pub artist: String,
+    /// The TSOP frame of the artist, or an empty string.
+    pub artist_sort: String,
+    pub album: String,
+    pub genre: String,
+    pub track_number: u32,
+}
+
+impl Track {
+    /// The value of the tag a view is keyed on.
+    pub fn value(&self, tag: &[u8]) -> &str {
+        match tag {
+            b"TALB" => &self.album,
+            b"TCON" => &self.genre,
+            b"TPE1" => &self.artist,
+            _ => &self.name,
+        }
+    }
+}
+
+/// The order of the titles in the view 2D: artist, album, track number.
+pub fn artist_album_order(collation: &Collation, track1: &Track, track2: &Track) -> Ordering {
+    artist_order(collation, track1, track2)
+        .then_with(|| collation.compare(&track1.album, &track2.album))
+        .then_with(|| track1.track_number.cmp(&track2.track_number))
+        .then_with(|| title_order(collation, track1, track2))
+}
+
+/// The order of the titles in a view keyed on one tag: the value of the tag, then the track number for an album.
+pub fn tag_order(collation: &Collation, tag: &[u8], track1: &Track, track2: &Track) -> Ordering {
+    let order =
+        match tag {
+            b"TPE1" => artist_order(collation, track1, track2),
+            b"TALB" => collation.compare(&track1.album, &track2.album)
+                .then_with(|| track1.track_number.cmp(&track2.track_number)),
+            _ => collation.compare(track1.value(tag), track2.value(tag)),
+        };
+    order.then_with(|| title_order(collation, track1, track2))
+}
+
+impl OmaDatabase {
+    /// Set the sort name of the artist in 04CNTINF and 03GINF01, then sort the views again.
+    /// An empty sort name sorts the artist by its name.
+    pub fn set_sort_name(&mut self, artist: &str, sort_name: &str) -> Result<()> {
+        let mut found = false;
+        for element in self.cntinf.cnfb_mut()? {
+            if element.text(b"TPE1")? == artist {
+                element.set_text(b"TSOP", sort_name)?;
+                found = true;
+            }
+        }
+        if !found {
+            return Err(format!("No title of the artist {} in 04CNTINF", artist));
+        }
+        if let Some(ginf) = self.ginfs.get_mut(&UPLOADED_VIEW) {
+            for element in ginf.gpfb_mut()? {
+                if element.frame(b"TSOP").is_some() && element.text(b"TPE1")? == artist {
+                    element.set_text(b"TSOP", sort_name)?;
+                }
+            }
+        }
+        self.link()?;
+        sort_views(self)
+    }
+}
+
+/// Sort GPLB and TPLB of the views again, after the metadata of titles changed.
+/// The tables are changed in memory: call `save` to write them.
+pub fn sort_views(database: &mut OmaDatabase) -> Result<()> {
+    rebuild_views(database, Change::Sorted)
+}
+
+fn artist_order(collation: &Collation, track1: &Track, track2: &Track) -> Ordering {
+    collation.compare_names(&track1.artist, &track1.artist_sort, &track2.artist, &track2.artist_sort)
+}
+
+fn title_order(collation: &Collation, track1: &Track, track2: &Track) -> Ordering {
+    collation.compare(&track1.name, &track2.name)
+        .then_with(|| track1.id.cmp(&track2.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::fs;
+
+    use collation::Collation;
+    use database::OmaDatabase;
+    use database::tests::{FIXTURE_TRACKS, fixtures_copy};
+    use parser::TitleId;
+    use tree::{artist_albums, groups};
+    use verify::verify;
+    use super::{Track, artist_album_order, tag_order};
+
+    fn track(id: TitleId, name: &str, artist: &str, album: &str, genre: &str, track_number: u32) -> Track {
+        Track {
+            id,
+            name: name.to_string(),
+            artist: artist.to_string(),
+            artist_sort: String::new(),
+            album: album.to_string(),
+            genre: genre.to_string(),
+            track_number,
+        }
+    }
+
+    #[test]
+    fn view_orders() {
+        let collation = Collation::default();
+        let tracks = [
+            track(1, "Yellow", "Coldplay", "Parachutes", "Rock", 5),
+            track(2, "Shiver", "Coldplay", "Parachutes", "Rock", 2),
+            track(3, "Clocks", "Coldplay", "A Rush of Blood", "Rock", 5),
+            track(4, "Hunter", "björk", "Homogenic", "Electronic", 3),
+            track(5, "Bachelorette", "björk", "Homogenic", "Electronic", 8),
+            track(6, "The Scientist", "The Coldplay Tribute", "Covers", "Pop", 1),
+        ];
+        let sorted = |order: &dyn Fn(&Track, &Track) -> Ordering| {
+            let mut sorted: Vec<_> = tracks.iter().collect();
+            sorted.sort_by(|track1, track2| order(track1, track2));
+            sorted.iter().map(|track| track.id).collect::<Vec<_>>()
+        };
+        // 02: the artists, "The" being ignored, then the title names.
+        assert_eq!(sorted(&|track1, track2| tag_order(&collation, b"TPE1", track1, track2)), vec![5, 4, 3, 2, 1, 6]);
+        // 03: the albums, then the track numbers.
+        assert_eq!(sorted(&|track1, track2| tag_order(&collation, b"TALB", track1, track2)), vec![3, 6, 4, 5, 2, 1]);
+        // 04: the genres, then the title names.
+        assert_eq!(sorted(&|track1, track2| tag_order(&collation, b"TCON", track1, track2)), vec![5, 4, 6, 3, 2, 1]);
+        // 2D: the artists, the albums, then the track numbers.
+        assert_eq!(sorted(&|track1, track2| artist_album_order(&collation, track1, track2)), vec![4, 5, 3, 2, 1, 6]);
+    }
+
+    #[test]
+    fn sort_by_sort_name() {
+        let path = fixtures_copy("sort", FIXTURE_TRACKS);
+        let mut database = OmaDatabase::open(&path).unwrap();
+        database.set_sort_name("Radiohead", "Aaa").unwrap();
+        database.save().unwrap();
+
+        let database = OmaDatabase::open(&path).unwrap();
+        assert!(verify(&database).unwrap().is_empty());
+        let names = database.ginfs[&0x2D].gpfb().unwrap();
+        let artists: Vec<_> = artist_albums(&database.trees[&0x2D]).unwrap().iter()
+            .map(|artist| names[artist.ginf_item as usize - 1].text(b"TIT2").unwrap())
+            .collect();
+        assert_eq!(artists, vec!["", "Radiohead", "Archive"]);
+        let titles: Vec<_> = groups(&database.trees[&2]).unwrap().into_iter()
+            .filter(|group| group.used)
+            .map(|group| group.titles)
+            .collect();
+        assert_eq!(titles, vec![vec![4, 2, 3, 1], vec![7, 9, 6, 10, 5]]);
+        // The used groups are before the unused ones, like the items 05 and 06 of 01TREE2D.
+        assert!(database.trees[&0x2D].gplb().unwrap().iter().any(|element| !element.association.is_used()));
+        for &view in &[2, 3, 4, 0x2D] {
+            let gplb = database.trees[&view].gplb().unwrap();
+            let used = gplb.iter().filter(|element| element.association.is_used()).count();
+            assert!(gplb[..used].iter().all(|element| element.association.is_used()));
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,472 @@
+/*
+
+# 10F0X folders
+
+The title whose title_id is 0x1B is the file "1000001B.OMA", in one of the folders 10F00, 10F01...
+The notes do not tell how many files a folder holds, so it is read from the existing folders (see `files_per_folder`):
+with 0x100 files per folder, the title_id 0x1B is in 10F00 and the title_id 0x11B is in 10F01. The existing files are
+found in any folder.
+
+The title_id of a title of 04CNTINF without file is free, and it must be used again before max_title_id grows. So
+Storage scans the 10F0X folders once, and its TitleAllocator hands out the free title_ids of 04CNTINF, then the
+title_ids after the last title of 04CNTINF. A file of 10F0X whose title is not in 04CNTINF is a leftover, which is overwritten.
+
+# Saving
+
+The files of 10F0X must match the tables, so the changes of the files wait in Storage until the tables are saved:
+- the tables are written in memory first, so that an invalid table changes nothing,
+- the files are written, renamed and deleted in the order of the changes, a file which is replaced or deleted being
+  only moved aside (with the extension .DEL),
+- the tables are written,
+- the files moved aside are deleted.
+When a step fails, the previous steps are undone, as far as possible, and the previous tables are written back.
+
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use parser::{Result, TitleId};
+
+/// The number of files per folder when the existing folders do not tell it.
+/// This is an assumption, which is not taken from the notes nor from a device.
+pub const DEFAULT_FILES_PER_FOLDER: usize = 0x100;
+
+/// The title_ids for new titles: the free title_ids of 04CNTINF, then new title_ids.
+pub struct TitleAllocator {
+    stored: BTreeSet<TitleId>,
+    title_count: usize,
+    next: Option<TitleId>,
+}
+
+impl TitleAllocator {
+    /// The allocator for the title_ids which have a file, when 04CNTINF lists `title_count` titles.
+    pub fn new(stored: BTreeSet<TitleId>, title_count: usize) -> Self {
+        TitleAllocator {
+            stored,
+            title_count,
+            next: Some(1),
+        }
+    }
+}
+
+impl Iterator for TitleAllocator {
+    type Item = TitleId;
+
+    fn next(&mut self) -> Option<TitleId> {
+        let mut title_id = self.next?;
+        while title_id as usize <= self.title_count && self.stored.contains(&title_id) {
+            title_id = title_id.checked_add(1)?;
+        }
+        self.next = title_id.checked_add(1);
+        Some(title_id)
+    }
+}
+
+/// The files of an OMGAUDIO folder, with the changes which are applied when saving the tables.
+pub struct Storage {
+    path: PathBuf,
+    /// The files of the titles, after the changes.
+    titles: BTreeMap<TitleId, PathBuf>,
+    files_per_folder: usize,
+    changes: Vec<FileChange>,
+}
+
+enum FileChange {
+    Write(PathBuf, Vec<u8>),
+    Rename(PathBuf, PathBuf),
+    Delete(PathBuf),
+}
+
+/// How to undo a step of `Storage::save`.
+enum Undo {
+    /// A file which did not exist.
+    Remove(PathBuf),
+    /// A file renamed from the second path to the first one.
+    Rename(PathBuf, PathBuf),
+    /// A file moved aside to the first path, which is deleted once the tables are saved.
+    Restore(PathBuf, PathBuf),
+    /// A table, with its previous content.
+    Rewrite(PathBuf, Vec<u8>),
+}
+
+impl Storage {
+    pub fn open(path: &Path) -> Self {
+        let titles = stored_title_files(path);
+        Storage {
+            path: path.to_path_buf(),
+            files_per_folder: files_per_folder(&titles),
+            titles,
+            changes: vec![],
+        }
+    }
+
+    /// The title_ids for new titles, given the number of titles of 04CNTINF.
+    pub fn allocator(&self, title_count: usize) -> TitleAllocator {
+        TitleAllocator::new(self.titles.keys().cloned().collect(), title_count)
+    }
+
+    /// Delete the file of the title, if any.
+    pub fn delete_title(&mut self, title_id: TitleId) {
+        if let Some(path) = self.titles.remove(&title_id) {
+            self.changes.push(FileChange::Delete(path));
+        }
+    }
+
+
+    /// The title_ids of 04CNTINF without file, given the number of titles of 04CNTINF.
+    pub fn free_titles(&self, title_count: usize) -> Vec<TitleId> {
+        self.allocator(title_count)
+            .take_while(|&title_id| title_id as usize <= title_count)
+            .collect()
+    }
+
+    pub fn has_title(&self, title_id: TitleId) -> bool {
+        self.titles.contains_key(&title_id)
+    }
+
+    /// Read the file, or its first `limit` bytes, with the changes.
+    pub fn read(&self, path: &Path, limit: usize) -> Result<Vec<u8>> {
+        let mut path = path.to_path_buf();
+        for change in self.changes.iter().rev() {
+            match *change {
+                FileChange::Write(ref written, ref content) if *written == path =>
+                    return Ok(content[..content.len().min(limit)].to_vec()),
+                FileChange::Rename(ref from, ref to) if *to == path => path = from.clone(),
+                FileChange::Rename(ref from, _) | FileChange::Delete(ref from) if *from == path =>
+                    return Err(format!("{}: deleted", path.display())),
+                _ => (),
+            }
+        }
+        let mut buffer = vec![];
+        File::open(&path)
+            .and_then(|file| file.take(limit as u64).read_to_end(&mut buffer))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(buffer)
+    }
+
+    /// Read the file of the title, or its first `limit` bytes, with the changes.
+    pub fn read_title(&self, title_id: TitleId, limit: usize) -> Result<Vec<u8>> {
+        let path = self.titles.get(&title_id)
+            .ok_or_else(|| format!("No file for the title {:04X}", title_id))?;
+        self.read(path, limit)
+    }
+
+    /// Give the file of the title to another title, which must have no file, when saving the tables.
+    pub fn rename_title(&mut self, title_id: TitleId, new_id: TitleId) -> Result<()> {
+        if self.titles.contains_key(&new_id) {
+            return Err(format!("The title {:04X} already has a file", new_id));
+        }
+        let path = self.titles.remove(&title_id)
+            .ok_or_else(|| format!("No file for the title {:04X}", title_id))?;
+        let new_path = title_file(&self.path, new_id, self.files_per_folder);
+        self.titles.insert(new_id, new_path.clone());
+        self.changes.push(FileChange::Rename(path, new_path));
+        Ok(())
+    }
+
+    /// Apply the changes of the files around the writing of the tables, as described in "Saving".
+    pub fn save(&mut self, tables: &[(PathBuf, Vec<u8>)]) -> Result<()> {
+        let mut undos = vec![];
+        if let Err(err) = self.apply(tables, &mut undos) {
+            for undo in undos.into_iter().rev() {
+                // The first error is the one reported.
+                let _ = undo.undo();
+            }
+            return Err(err);
+        }
+        self.changes.clear();
+        for undo in undos {
+            if let Undo::Restore(aside, _) = undo {
+                fs::remove_file(&aside)
+                    .map_err(|err| format!("{}: {}", aside.display(), err))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the file when saving the tables.
+    pub fn write(&mut self, path: PathBuf, content: Vec<u8>) {
+        self.changes.push(FileChange::Write(path, content));
+    }
+
+    /// Write the file of the title when saving the tables, replacing the previous one.
+    pub fn write_title(&mut self, title_id: TitleId, content: Vec<u8>) {
+        let path = self.titles.get(&title_id).cloned()
+            .unwrap_or_else(|| title_file(&self.path, title_id, self.files_per_folder));
+        self.titles.insert(title_id, path.clone());
+        self.write(path, content);
+    }
+
+    fn apply(&self, tables: &[(PathBuf, Vec<u8>)], undos: &mut Vec<Undo>) -> Result<()> {
+        for change in &self.changes {
+            match *change {
+                FileChange::Write(ref path, ref content) => {
+                    move_aside(path, undos)?;
+                    write_file(path, content)?;
+                    undos.push(Undo::Remove(path.clone()));
+                },
+                FileChange::Rename(ref from, ref to) => {
+                    move_aside(to, undos)?;
+                    if let Some(folder) = to.parent() {
+                        fs::create_dir_all(folder)
+                            .map_err(|err| format!("{}: {}", folder.display(), err))?;
+                    }
+                    fs::rename(from, to)
+                        .map_err(|err| format!("{}: {}", from.display(), err))?;
+                    undos.push(Undo::Rename(to.clone(), from.clone()));
+                },
+                FileChange::Delete(ref path) => move_aside(path, undos)?,
+            }
+        }
+        for (path, content) in tables {
+            let undo =
+                match fs::read(path) {
+                    Ok(previous) => Undo::Rewrite(path.clone(), previous),
+                    Err(_) => Undo::Remove(path.clone()),
+                };
+            write_file(path, content)?;
+            undos.push(undo);
+        }
+        Ok(())
+    }
+}
+
+impl Undo {
+    fn undo(self) -> ::std::io::Result<()> {
+        match self {
+            Undo::Remove(path) => fs::remove_file(path),
+            Undo::Rename(path, previous) | Undo::Restore(path, previous) => fs::rename(path, previous),
+            Undo::Rewrite(path, content) => fs::write(path, content),
+        }
+    }
+}
+
+/// Find a file in the folder, ignoring the case of its name, as the device uses a FAT file system.
+pub fn find_file(path: &Path, name: &str) -> Option<PathBuf> {
+    let entries = fs::read_dir(path).ok()?;
+    entries.filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_name().to_string_lossy().eq_ignore_ascii_case(name))
+        .map(|entry| entry.path())
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(buffer)
+}
+
+/// Write the file, creating its folder if needed.
+pub fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(folder) = path.parent() {
+        fs::create_dir_all(folder)
+            .map_err(|err| format!("{}: {}", folder.display(), err))?;
+    }
+    let mut file = File::create(path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    file.write_all(content)
+        .map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// The number of files per 10F0X folder, read from the files of the titles.
+/// The title_id X in the folder 10F0N means that N * count <= X < (N + 1) * count: the count is the largest one
+/// which agrees with every file, so the lowest title_id of a folder after 10F00 gives it.
+/// Without such a folder, the files are not split when 10F00 holds title_ids after DEFAULT_FILES_PER_FOLDER, else the
+/// count is DEFAULT_FILES_PER_FOLDER. It is also DEFAULT_FILES_PER_FOLDER when the folders do not agree.
+pub fn files_per_folder(files: &BTreeMap<TitleId, PathBuf>) -> usize {
+    let mut lowest = 1;
+    let mut highest = None;
+    for (&title_id, path) in files {
+        let folder = match path.parent().and_then(folder_number) {
+            Some(folder) => folder,
+            None => continue,
+        };
+        let title_id = title_id as usize;
+        lowest = lowest.max(title_id / (folder + 1) + 1);
+        // The folder 10F00 gives no highest count.
+        if let Some(count) = title_id.checked_div(folder) {
+            highest = Some(highest.unwrap_or(usize::MAX).min(count));
+        }
+    }
+    match highest {
+        Some(highest) if highest >= lowest => highest,
+        Some(_) => DEFAULT_FILES_PER_FOLDER,
+        None if lowest > DEFAULT_FILES_PER_FOLDER => TitleId::MAX as usize + 1,
+        None => DEFAULT_FILES_PER_FOLDER,
+    }
+}
+
+/// The files of the 10F0X folders, by title_id, reading each folder once.
+pub fn stored_title_files(path: &Path) -> BTreeMap<TitleId, PathBuf> {
+    let mut files = BTreeMap::new();
+    for folder in entries(path).into_iter().filter(|folder| folder_number(folder).is_some()) {
+        for file in entries(&folder) {
+            if let Some(title_id) = title_id(&file) {
+                files.insert(title_id, file);
+            }
+        }
+    }
+    files
+}
+
+/// The title_id of a file named like "1000001B.OMA".
+pub fn title_id(path: &Path) -> Option<TitleId> {
+    let name = path.file_name()?.to_str()?.to_ascii_uppercase();
+    if name.len() != 12 || !name.starts_with("1000") || !name.ends_with(".OMA") {
+        return None;
+    }
+    TitleId::from_str_radix(&name[4..8], 16).ok()
+        .filter(|&title_id| title_id != 0)
+}
+
+/// The path where the file of the title is written in the OMGAUDIO folder, reusing the existing 10F0X folder.
+pub fn title_file(path: &Path, title_id: TitleId, files_per_folder: usize) -> PathBuf {
+    let folder_name = format!("10F{:02X}", title_id as usize / files_per_folder);
+    let folder = find_file(path, &folder_name)
+        .unwrap_or_else(|| path.join(&folder_name));
+    find_file(&folder, &file_name(title_id))
+        .unwrap_or_else(|| folder.join(file_name(title_id)))
+}
+
+/// Move the file, if it exists, to the same path with the extension .DEL.
+fn move_aside(path: &Path, undos: &mut Vec<Undo>) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut aside = OsString::from(path);
+    aside.push(".DEL");
+    let aside = PathBuf::from(aside);
+    fs::rename(path, &aside)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    undos.push(Undo::Restore(aside, path.to_path_buf()));
+    Ok(())
+}
+
+fn entries(path: &Path) -> Vec<PathBuf> {
+    fs::read_dir(path).map(|entries| {
+        entries.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect()
+    }).unwrap_or_default()
+}
+
+fn file_name(title_id: TitleId) -> String {
+    format!("1000{:04X}.OMA", title_id)
+}
+
+/// The number N of a folder named like "10F0N".
+fn folder_number(path: &Path) -> Option<usize> {
+    let name = path.file_name()?.to_str()?.to_ascii_uppercase();
+    if name.len() != 5 || !name.starts_with("10F") {
+        return None;
+    }
+    usize::from_str_radix(&name[3..], 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use parser::TitleId;
+    use super::{DEFAULT_FILES_PER_FOLDER, Storage, files_per_folder, read_file, title_file, write_file};
+
+    /// An empty OMGAUDIO folder in the temporary folder.
+    fn omgaudio(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rs-storage-{}-{}", name, process::id())).join("OMGAUDIO");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn add_files(path: &Path, folder: &str, title_ids: &[TitleId]) {
+        for &title_id in title_ids {
+            write_file(&path.join(folder).join(format!("1000{:04X}.OMA", title_id)), b"OMA").unwrap();
+        }
+    }
+
+    fn folders(files: &[(&str, TitleId)]) -> BTreeMap<TitleId, PathBuf> {
+        files.iter()
+            .map(|&(folder, title_id)| (title_id, Path::new(folder).join(format!("1000{:04X}.OMA", title_id))))
+            .collect()
+    }
+
+    #[test]
+    fn allocate_free_titles_first() {
+        let path = omgaudio("free");
+        add_files(&path, "10F00", &[1, 2, 4, 6]);
+        let storage = Storage::open(&path);
+        assert_eq!(storage.free_titles(6), vec![3, 5]);
+        let title_ids: Vec<_> = storage.allocator(6).take(4).collect();
+        assert_eq!(title_ids, vec![3, 5, 7, 8]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn overwrite_leftover_files() {
+        let path = omgaudio("leftover");
+        // 04CNTINF lists 4 titles: the files 6 and 7 are leftovers.
+        add_files(&path, "10F00", &[1, 2, 3, 4, 6, 7]);
+        let mut storage = Storage::open(&path);
+        assert!(storage.free_titles(4).is_empty());
+        let title_ids: Vec<_> = storage.allocator(4).take(3).collect();
+        assert_eq!(title_ids, vec![5, 6, 7]);
+
+        storage.write_title(6, b"NEW".to_vec());
+        assert_eq!(storage.read_title(6, 0x100).unwrap(), b"NEW");
+        storage.save(&[]).unwrap();
+        assert_eq!(read_file(&path.join("10F00/10000006.OMA")).unwrap(), b"NEW");
+        assert!(!path.join("10F00/10000006.OMA.DEL").exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn title_files_across_folders() {
+        let path = omgaudio("folders");
+        add_files(&path, "10F00", &[0x01, 0xFF]);
+        add_files(&path, "10F01", &[0x100, 0x1B2]);
+        let storage = Storage::open(&path);
+        assert_eq!(storage.files_per_folder, 0x100);
+        assert!(storage.has_title(0x1B2));
+        assert_eq!(title_file(&path, 0xFF, 0x100), path.join("10F00").join("100000FF.OMA"));
+        assert_eq!(title_file(&path, 0x100, 0x100), path.join("10F01").join("10000100.OMA"));
+        assert_eq!(title_file(&path, 0x200, 0x100), path.join("10F02").join("10000200.OMA"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn read_files_per_folder() {
+        assert_eq!(files_per_folder(&folders(&[("10F00", 0x01), ("10F01", 0x80), ("10F02", 0x105)])), 0x80);
+        // The lowest title_id of 10F01 is not the first one of the folder.
+        assert_eq!(files_per_folder(&folders(&[("10F00", 0x10), ("10F01", 0x130)])), 0x130);
+        assert_eq!(files_per_folder(&folders(&[("10F00", 0x01), ("10F00", 0xFF)])), DEFAULT_FILES_PER_FOLDER);
+        assert_eq!(files_per_folder(&folders(&[("10F00", 0x01), ("10F00", 0x150)])), 0x10000);
+        assert_eq!(files_per_folder(&folders(&[("10F00", 0x150), ("10F01", 0x110)])), DEFAULT_FILES_PER_FOLDER);
+        assert_eq!(files_per_folder(&BTreeMap::new()), DEFAULT_FILES_PER_FOLDER);
+    }
+
+    #[test]
+    fn undo_failed_save() {
+        let path = omgaudio("undo");
+        add_files(&path, "10F00", &[1, 2]);
+        fs::create_dir(path.join("04CNTINF.DAT")).unwrap();
+        let mut storage = Storage::open(&path);
+        storage.delete_title(1);
+        storage.write_title(3, b"NEW".to_vec());
+        // A table which cannot be written.
+        assert!(storage.save(&[(path.join("04CNTINF.DAT"), vec![0; 16])]).is_err());
+        assert_eq!(read_file(&path.join("10F00/10000001.OMA")).unwrap(), b"OMA");
+        assert!(!path.join("10F00/10000001.OMA.DEL").exists());
+        assert!(!path.join("10F00/10000003.OMA").exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
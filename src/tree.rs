@@ -0,0 +1,115 @@
+use parser::{Association, Result, Table, TitleId};
+
+/// A group of a view (an album, an artist, a genre...) with its titles, as listed in 01TREEXX.
+pub struct Group {
+    /// The item_id of the group in 03GINFXX.
+    pub ginf_item: u16,
+    pub used: bool,
+    /// The title_id_in_TPLBlist of the first title of the group.
+    pub first: u16,
+    pub titles: Vec<TitleId>,
+}
+
+/// An artist of 01TREE2D, with its albums.
+pub struct ArtistGroup {
+    /// The item_id of the artist in 03GINF2D.
+    pub ginf_item: u16,
+    pub albums: Vec<Group>,
+}
+
+/// Resolve 01TREE2D into artists, each followed by its albums in GPLB: artist → albums → titles.
+/// The unused items are skipped.
+pub fn artist_albums(tree: &Table) -> Result<Vec<ArtistGroup>> {
+    let mut artists: Vec<ArtistGroup> = vec![];
+    for (element, group) in tree.gplb()?.iter().zip(groups(tree)?) {
+        match element.association {
+            // The used groups which are not albums are the artists.
+            Association::Used => artists.push(ArtistGroup {
+                ginf_item: element.id,
+                albums: vec![],
+            }),
+            Association::Album => {
+                let artist = artists.last_mut()
+                    .ok_or_else(|| format!("The album {} is not preceded by an artist", element.id))?;
+                artist.albums.push(group);
+            },
+            Association::Unused => (),
+            Association::Other(value) => return Err(format!("Unknown association {:x} for item {}", value, element.id)),
+        }
+    }
+    Ok(artists)
+}
+
+/// Resolve the GPLB and TPLB classes of a 01TREEXX table into groups, in GPLB order.
+/// The titles of a used group start at its title_id_in_TPLBlist and end at the start of the next group.
+/// When several groups start at the same title, the titles belong to the last of them.
+/// The titles before the first used group do not belong to any group.
+pub fn groups(tree: &Table) -> Result<Vec<Group>> {
+    let elements = tree.gplb()?;
+    let title_ids = tree.tplb()?;
+    let mut groups: Vec<_> = elements.iter()
+        .map(|element| Group {
+            ginf_item: element.id,
+            used: element.association.is_used(),
+            first: element.title_id,
+            titles: vec![],
+        })
+        .collect();
+
+    let mut starts: Vec<_> = groups.iter().enumerate()
+        .filter(|&(_, group)| group.used)
+        .map(|(index, group)| (group.first, index))
+        .collect();
+    starts.sort();
+    let mut start = starts.iter().peekable();
+    let mut current = None;
+    for (index, element) in title_ids.iter().enumerate() {
+        let index = index as u16 + 1;
+        while let Some(&&(first, group)) = start.peek() {
+            if first > index {
+                break;
+            }
+            current = Some(group);
+            start.next();
+        }
+        if let Some(group) = current {
+            groups[group].titles.push(element.title_id);
+        }
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::parse_table;
+    use super::artist_albums;
+
+    /// The example of the 01TREE2D notes: Archive (Noise, Take My Head) and Radiohead (Amnesiac), the unused items
+    /// 05 and 06 at the tail of GPLB.
+    #[test]
+    fn resolve_artist_albums() {
+        let tree = parse_table(include_bytes!("../tests/fixtures/OMGAUDIO/01TREE2D.DAT")).unwrap();
+        let ginf = parse_table(include_bytes!("../tests/fixtures/OMGAUDIO/03GINF2D.DAT")).unwrap();
+        let names = ginf.gpfb().unwrap();
+        let name = |item: u16| names[item as usize - 1].text(b"TIT2").unwrap();
+
+        let artists: Vec<_> = artist_albums(&tree).unwrap().iter()
+            .map(|artist| {
+                let albums: Vec<_> = artist.albums.iter()
+                    .map(|album| (name(album.ginf_item), album.first, album.titles.clone()))
+                    .collect();
+                (name(artist.ginf_item), albums)
+            })
+            .collect();
+        assert_eq!(artists, vec![
+            (String::new(), vec![]),
+            ("Archive".to_string(), vec![
+                ("Noise".to_string(), 1, vec![0x09, 0x0A]),
+                ("Take My Head".to_string(), 3, vec![0x05, 0x06, 0x07]),
+            ]),
+            ("Radiohead".to_string(), vec![
+                ("Amnesiac".to_string(), 6, vec![0x01, 0x02, 0x03, 0x04]),
+            ]),
+        ]);
+    }
+}
@@ -0,0 +1,268 @@
+/*
+
+# Adding titles
+
+Adding titles follows the "Adding titles" section of each table:
+- the titles are sorted by artist, album and track number, then they take the free title_ids (the titles of 04CNTINF
+  without file in 10F0X) before new title_ids are created, as handed out by the TitleAllocator of storage,
+- their elements are written in 04CNTINF and 05CIDLST at the place of their title_id, and their files in 10F0X,
+- they are uploaded at the end of 01TREE01, their artists, albums and genres are added at the end of 03GINFXX if they are
+  not already there, and the other views are sorted again,
+- the keys are recomputed.
+
+# Deleting titles
+
+Deleting titles follows the "Deleting titles" section of each table:
+- their elements stay in 04CNTINF and 05CIDLST: their title_ids become free,
+- they are removed from every TPLB, the empty groups go to the unused tail of GPLB and the lists are rebuilt,
+- the items of 03GINFXX are kept: the unused albums of 03GINF01 are blank elements, which take the next uploaded albums,
+- the keys are recomputed,
+- their files are deleted from 10F0X once the tables are written.
+
+*/
+
+use std::path::{Path, PathBuf};
+
+use database::OmaDatabase;
+use parser::{CilbElement, CnfbElement, FRAME_SIZE, Frame, Result, TitleId};
+use parser::music::{MP3_FORMAT, NO_PROTECTION, Tag, mpeg_length, parse_id3};
+use repair::{Change, rebuild_views};
+use storage::read_file;
+use writer::music::{mp3_to_oma, strip_id3};
+
+const CNFB_FRAMES: &[&[u8]] = &[b"TIT2", b"TPE1", b"TALB", b"TCON", b"TSOP"];
+
+/// A title to add to the device: the content of an MP3 file and the tag written in the header of its OMA file.
+/// When the tag has no length, it is computed from the MPEG frames.
+pub struct TrackSource {
+    /// The MP3 file, named in the errors.
+    pub path: PathBuf,
+    pub mp3: Vec<u8>,
+    pub tag: Tag,
+}
+
+impl TrackSource {
+    /// The MP3 file, described by its ID3 tag.
+    pub fn from_mp3<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mp3 = read_file(&path)?;
+        let tag = parse_id3(&mp3)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(TrackSource {
+            path,
+            mp3,
+            tag,
+        })
+    }
+}
+
+impl OmaDatabase {
+    /// Add the titles to the device and return their title_ids, in the order of `sources`.
+    /// The files are written in 10F0X with the tables, when calling `save`.
+    pub fn add_tracks(&mut self, sources: &[TrackSource]) -> Result<Vec<TitleId>> {
+        let mut order: Vec<_> = (0..sources.len()).collect();
+        let collation = &self.collation;
+        order.sort_by(|&index1, &index2| {
+            let (tag1, tag2) = (&sources[index1].tag, &sources[index2].tag);
+            collation.compare_names(text(&tag1.artist), text(&tag1.sort_name), text(&tag2.artist), text(&tag2.sort_name))
+                .then_with(|| collation.compare(text(&tag1.album), text(&tag2.album)))
+                .then_with(|| tag1.track_number.cmp(&tag2.track_number))
+                .then_with(|| collation.compare(text(&tag1.title), text(&tag2.title)))
+        });
+
+        let mut titles = vec![];
+        for &index in &order {
+            let source = &sources[index];
+            let mut tag = source.tag.clone();
+            if tag.length.is_none() {
+                tag.length = mpeg_length(strip_id3(&source.mp3));
+            }
+            if tag.length.is_none() {
+                return Err(format!("{}: Cannot find the length of the title", source.path.display()));
+            }
+            let oma = mp3_to_oma(&source.mp3, &tag)?;
+            titles.push((index, tag, oma));
+        }
+
+        let mut title_ids = vec![0; sources.len()];
+        let mut added = vec![];
+        let mut allocator = self.storage.allocator(self.cntinf.cnfb()?.len());
+        for (index, tag, oma) in titles {
+            let title_id = allocator.next()
+                .ok_or("No title_id left in 10F0X")?;
+            self.storage.write_title(title_id, oma);
+            self.set_title(title_id, &tag)?;
+            title_ids[index] = title_id;
+            added.push(title_id);
+        }
+        self.cntinf.relayout();
+        self.cidlst.relayout();
+        self.link()?;
+        rebuild_views(self, Change::Added(&added))?;
+        Ok(title_ids)
+    }
+
+    /// Delete the titles from the device.
+    /// The files are deleted from 10F0X after the tables are written, when calling `save`.
+    pub fn remove_tracks(&mut self, title_ids: &[TitleId]) -> Result<()> {
+        let count = self.cntinf.cnfb()?.len();
+        if let Some(title_id) = title_ids.iter().find(|&&title_id| title_id == 0 || title_id as usize > count) {
+            return Err(format!("No title {:04X} in 04CNTINF", title_id));
+        }
+        rebuild_views(self, Change::Removed(title_ids))?;
+        for &title_id in title_ids {
+            self.storage.delete_title(title_id);
+        }
+        Ok(())
+    }
+
+    /// Write the elements of the title in 04CNTINF and 05CIDLST, at the place of its title_id.
+    fn set_title(&mut self, title_id: TitleId, tag: &Tag) -> Result<()> {
+        let texts = [&tag.title, &tag.artist, &tag.album, &tag.genre, &tag.sort_name];
+        let element = CnfbElement {
+            reserved: vec![0; 2],
+            protection: NO_PROTECTION,
+            file_properties: MP3_FORMAT,
+            title_key: tag.length.unwrap_or(0),
+            part_count: CNFB_FRAMES.len() as u16,
+            part_size: FRAME_SIZE,
+            frames: CNFB_FRAMES.iter().zip(&texts)
+                .map(|(frame_tag, value)| Frame::new(frame_tag, text(value), FRAME_SIZE))
+                .collect(),
+            trailing: vec![],
+        };
+        let index = title_id as usize - 1;
+        let cnfb = self.cntinf.cnfb_mut()?;
+        if index < cnfb.len() {
+            cnfb[index] = element;
+        }
+        else {
+            cnfb.push(element);
+        }
+
+        let cilb = self.cidlst.cilb_mut()?;
+        while cilb.len() <= index {
+            let title_id = cilb.len() as TitleId + 1;
+            cilb.push(CilbElement {
+                title_id,
+                drmed_oma_key: vec![0; 0x18],
+                trailing: vec![],
+            });
+        }
+        cilb[index].drmed_oma_key = vec![0; 0x18];
+        Ok(())
+    }
+}
+
+fn text(value: &Option<String>) -> &str {
+    value.as_ref().map(String::as_str).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use database::OmaDatabase;
+    use database::tests::{FIXTURE_TRACKS, fixtures_copy};
+    use parser::TitleId;
+    use parser::music::Tag;
+    use parser::music::tests::mpeg_frames;
+    use tree::groups;
+    use verify::verify;
+    use super::TrackSource;
+
+    fn source(title: &str, track_number: u32) -> TrackSource {
+        TrackSource {
+            path: PathBuf::from(format!("{}.mp3", title)),
+            // 40 frames of 26.122 ms.
+            mp3: mpeg_frames(40),
+            tag: Tag {
+                title: Some(title.to_string()),
+                artist: Some("Muse".to_string()),
+                album: Some("Absolution".to_string()),
+                genre: Some("Rock".to_string()),
+                track_number: Some(track_number),
+                ..Tag::default()
+            },
+        }
+    }
+
+    fn artist_key(database: &OmaDatabase, name: &str) -> u32 {
+        database.ginfs[&2].gpfb().unwrap().iter()
+            .find(|element| element.text(b"TIT2").unwrap() == name)
+            .unwrap()
+            .key
+    }
+
+    /// The names of the used groups of the view, with their titles.
+    fn view_groups(database: &OmaDatabase, view: u8) -> Vec<(String, Vec<TitleId>)> {
+        let names = database.ginfs[&view].gpfb().unwrap();
+        groups(&database.trees[&view]).unwrap().into_iter()
+            .filter(|group| group.used)
+            .map(|group| (names[group.ginf_item as usize - 1].text(b"TIT2").unwrap(), group.titles))
+            .collect()
+    }
+
+    #[test]
+    fn add_tracks() {
+        let path = fixtures_copy("update-add", FIXTURE_TRACKS);
+        let mut database = OmaDatabase::open(&path).unwrap();
+        // The free title_id 8 is taken first, by the first track of the album.
+        let title_ids = database.add_tracks(&[source("Sing for absolution", 2), source("Apocalypse please", 1)]).unwrap();
+        assert_eq!(title_ids, vec![0x0B, 0x08]);
+        assert!(!path.join("10F00/1000000B.OMA").exists());
+        database.save().unwrap();
+        assert!(path.join("10F00/10000008.OMA").exists());
+        assert!(path.join("10F00/1000000B.OMA").exists());
+
+        let database = OmaDatabase::open(&path).unwrap();
+        assert!(verify(&database).unwrap().is_empty());
+        assert_eq!(database.cntinf.cnfb().unwrap().len(), 11);
+        assert_eq!(database.cidlst.cilb().unwrap().len(), 11);
+        let title = database.title(8).unwrap();
+        assert_eq!((title.name.as_str(), title.artist.as_str()), ("Apocalypse please", "Muse"));
+        assert_eq!(title.key, 1044);
+        assert_eq!(view_groups(&database, 1).last().unwrap(), &("Absolution".to_string(), vec![0x08, 0x0B]));
+        let artists: Vec<_> = view_groups(&database, 2).into_iter().map(|group| group.0).collect();
+        assert_eq!(artists, vec!["Archive", "Muse", "Radiohead"]);
+        let rock = view_groups(&database, 4).into_iter().find(|group| group.0 == "Rock").unwrap();
+        assert_eq!(rock.1, vec![0x08, 0x04, 0x02, 0x03, 0x01, 0x0B]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn remove_tracks() {
+        let path = fixtures_copy("update-remove", FIXTURE_TRACKS);
+        let mut database = OmaDatabase::open(&path).unwrap();
+        let removed_key = database.title(9).unwrap().key + database.title(10).unwrap().key;
+        let global_key = database.treinf.gtfb().unwrap()[0].global_key;
+        let archive_key = artist_key(&database, "Archive");
+        // The album Noise loses all its titles.
+        database.remove_tracks(&[9, 10]).unwrap();
+        assert!(path.join("10F00/10000009.OMA").exists());
+        database.save().unwrap();
+        assert!(!path.join("10F00/10000009.OMA").exists());
+        assert!(!path.join("10F00/1000000A.OMA").exists());
+
+        let database = OmaDatabase::open(&path).unwrap();
+        assert!(verify(&database).unwrap().is_empty());
+        // The elements stay in 04CNTINF.
+        assert_eq!(database.cntinf.cnfb().unwrap().len(), 10);
+        assert_eq!(database.cntinf.cnfb().unwrap()[8].text(b"TIT2").unwrap(), "Fuck U");
+        assert!(database.title(9).unwrap().positions.is_empty());
+        assert_eq!(database.treinf.gtfb().unwrap()[0].global_key, global_key - removed_key);
+        assert_eq!(artist_key(&database, "Archive"), archive_key - removed_key);
+
+        let albums: Vec<_> = view_groups(&database, 3).into_iter().map(|group| group.0).collect();
+        assert_eq!(albums, vec!["Amnesiac", "Take My Head"]);
+        for &view in &[3, 0x2D] {
+            let names = database.ginfs[&view].gpfb().unwrap();
+            let gplb = database.trees[&view].gplb().unwrap();
+            let noise = gplb.iter().position(|element| names[element.id as usize - 1].text(b"TIT2").unwrap() == "Noise").unwrap();
+            assert!(!gplb[noise].association.is_used());
+            assert!(gplb[noise..].iter().all(|element| !element.association.is_used()));
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+
+use database::OmaDatabase;
+use keys::{KeyLocation, verify_keys};
+use parser::{Result, Table, check_title_lists};
+use tree::groups;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A broken invariant, located by the name of the table file and the offset in this file.
+pub struct Violation {
+    pub severity: Severity,
+    pub file: String,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let severity =
+            match self.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+        write!(formatter, "{}: {} at 0x{:x}: {}", severity, self.file, self.offset, self.message)
+    }
+}
+
+/// Check the invariants between the tables of the database.
+pub fn verify(database: &OmaDatabase) -> Result<Vec<Violation>> {
+    let mut violations = vec![];
+    let mut tables = vec![
+        ("00GTRLST.DAT".to_string(), &database.gtrlst),
+        ("02TREINF.DAT".to_string(), &database.treinf),
+        ("04CNTINF.DAT".to_string(), &database.cntinf),
+        ("05CIDLST.DAT".to_string(), &database.cidlst),
+    ];
+    for (view, tree) in &database.trees {
+        tables.push((tree_file(*view), tree));
+    }
+    for (view, ginf) in &database.ginfs {
+        tables.push((ginf_file(*view), ginf));
+    }
+    for &(ref file, table) in &tables {
+        verify_layout(file, table, &mut violations);
+    }
+
+    if let Err(message) = check_title_lists(&database.cntinf, &database.cidlst) {
+        violations.push(Violation {
+            severity: Severity::Error,
+            file: "05CIDLST.DAT".to_string(),
+            offset: class_offset(&database.cidlst, b"CILB")? + 4,
+            message,
+        });
+    }
+
+    let title_count = database.cntinf.cnfb()?.len();
+
+    for (&view, tree) in &database.trees {
+        verify_tree(database, view, tree, title_count, &mut violations)?;
+    }
+
+    for mismatch in verify_keys(database)? {
+        let violation =
+            match mismatch.location {
+                KeyLocation::Group { view, item_id } => {
+                    let ginf = &database.ginfs[&view];
+                    let used = groups(&database.trees[&view])?.iter()
+                        .any(|group| group.used && group.ginf_item == item_id);
+                    // The notes are not sure that the key of an unused item has to be decreased.
+                    Violation {
+                        severity: if used { Severity::Error } else { Severity::Warning },
+                        file: ginf_file(view),
+                        offset: element_offset(ginf, b"GPFB", item_id as usize - 1)? + 8,
+                        message: format!("Key {:x} of item {} should be {:x}", mismatch.stored, item_id, mismatch.expected),
+                    }
+                },
+                KeyLocation::Global { view } => Violation {
+                    severity: Severity::Error,
+                    file: "02TREINF.DAT".to_string(),
+                    offset: element_offset(&database.treinf, b"GTFB", view as usize - 1)? + 8,
+                    message: format!("Global key {:x} of view {:02X} should be {:x}", mismatch.stored, view, mismatch.expected),
+                },
+            };
+        violations.push(violation);
+    }
+    Ok(violations)
+}
+
+fn class_offset(table: &Table, name: &[u8]) -> Result<usize> {
+    table.class_descriptions.iter()
+        .find(|description| description.name == name)
+        .map(|description| description.address as usize)
+        .ok_or_else(|| format!("No class {} in table {}", String::from_utf8_lossy(name), String::from_utf8_lossy(&table.name)))
+}
+
+fn element_offset(table: &Table, name: &[u8], index: usize) -> Result<usize> {
+    let class = table.class(name)?;
+    Ok(class_offset(table, name)? + 16 + index * class.element_length as usize)
+}
+
+fn ginf_file(view: u8) -> String {
+    format!("03GINF{:02X}.DAT", view)
+}
+
+fn tree_file(view: u8) -> String {
+    format!("01TREE{:02X}.DAT", view)
+}
+
+/// Check that the classes start and end at addresses ending with 0.
+fn verify_layout(file: &str, table: &Table, violations: &mut Vec<Violation>) {
+    for (index, description) in table.class_descriptions.iter().enumerate() {
+        let offset = 16 + index * 16;
+        if description.address % 0x10 != 0 {
+            violations.push(Violation {
+                severity: Severity::Error,
+                file: file.to_string(),
+                offset: offset + 4,
+                message: format!("Address {:x} of class {} does not end with 0", description.address, String::from_utf8_lossy(&description.name)),
+            });
+        }
+        if description.len % 0x10 != 0 {
+            violations.push(Violation {
+                severity: Severity::Error,
+                file: file.to_string(),
+                offset: offset + 8,
+                message: format!("Length {:x} of class {} does not end with 0", description.len, String::from_utf8_lossy(&description.name)),
+            });
+        }
+    }
+}
+
+fn verify_tree(database: &OmaDatabase, view: u8, tree: &Table, title_count: usize, violations: &mut Vec<Violation>) -> Result<()> {
+    let file = tree_file(view);
+    let ginf_count = database.ginfs[&view].gpfb()?.len();
+    let elements = tree.gplb()?;
+    if elements.len() != ginf_count {
+        violations.push(Violation {
+            severity: Severity::Error,
+            file: file.clone(),
+            offset: class_offset(tree, b"GPLB")? + 4,
+            message: format!("GPLB lists {} groups, but 03GINF{:02X} lists {} items", elements.len(), view, ginf_count),
+        });
+    }
+
+    let mut unused_seen = false;
+    for (index, element) in elements.iter().enumerate() {
+        let offset = element_offset(tree, b"GPLB", index)?;
+        if element.id == 0 || element.id as usize > ginf_count {
+            violations.push(Violation {
+                severity: Severity::Error,
+                file: file.clone(),
+                offset,
+                message: format!("Group {} refers to the item {} which is not in 03GINF{:02X}", index + 1, element.id, view),
+            });
+        }
+        if !element.association.is_used() {
+            unused_seen = true;
+        }
+        else if unused_seen {
+            violations.push(Violation {
+                severity: Severity::Error,
+                file: file.clone(),
+                offset,
+                message: format!("Used group {} is after an unused group", index + 1),
+            });
+        }
+    }
+
+    for (index, element) in tree.tplb()?.iter().enumerate() {
+        if element.title_id == 0 || element.title_id as usize > title_count {
+            violations.push(Violation {
+                severity: Severity::Error,
+                file: file.clone(),
+                offset: element_offset(tree, b"TPLB", index)?,
+                message: format!("Title {:04X} is not in 04CNTINF", element.title_id),
+            });
+        }
+    }
+
+    let in_groups: usize = groups(tree)?.iter().map(|group| group.titles.len()).sum();
+    let title_ids = tree.tplb()?.len();
+    if in_groups != title_ids {
+        violations.push(Violation {
+            severity: Severity::Warning,
+            file,
+            offset: class_offset(tree, b"TPLB")?,
+            message: format!("Only {} of the {} titles of TPLB belong to a group", in_groups, title_ids),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use database::OmaDatabase;
+    use parser::Association;
+    use super::{Severity, verify};
+
+    /// The violations of the fixtures, once changed: severity, file, offset and message.
+    fn violations<F: FnOnce(&mut OmaDatabase)>(change: F) -> Vec<(Severity, String, usize, String)> {
+        let mut database = OmaDatabase::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/OMGAUDIO")).unwrap();
+        change(&mut database);
+        verify(&database).unwrap().into_iter()
+            .map(|violation| (violation.severity, violation.file, violation.offset, violation.message))
+            .collect()
+    }
+
+    #[test]
+    fn verify_fixtures() {
+        assert!(violations(|_| ()).is_empty());
+    }
+
+    #[test]
+    fn broken_invariants() {
+        let error = |file: &str, offset, message: &str| (Severity::Error, file.to_string(), offset, message.to_string());
+        let warning = |file: &str, offset, message: &str| (Severity::Warning, file.to_string(), offset, message.to_string());
+
+        assert_eq!(violations(|database| database.trees.get_mut(&3).unwrap().class_descriptions[1].address += 8), vec![
+            error("01TREE03.DAT", 0x24, "Address 4048 of class TPLB does not end with 0"),
+        ]);
+        assert_eq!(violations(|database| {
+            database.ginfs.get_mut(&3).unwrap().gpfb_mut().unwrap().pop();
+        }), vec![
+            error("01TREE03.DAT", 0x34, "GPLB lists 4 groups, but 03GINF03 lists 3 items"),
+            error("01TREE03.DAT", 0x58, "Group 4 refers to the item 4 which is not in 03GINF03"),
+        ]);
+        // The title is also missing from the key of its artist.
+        assert_eq!(violations(|database| database.trees.get_mut(&2).unwrap().tplb_mut().unwrap()[1].title_id = 0x20), vec![
+            error("01TREE02.DAT", 0x4052, "Title 0020 is not in 04CNTINF"),
+            error("03GINF02.DAT", 0xC8, "Key c6bb0 of item 2 should be a25a8"),
+        ]);
+        // The titles of the group do not count anymore, and the key of its item should be 0.
+        assert_eq!(violations(|database| {
+            database.trees.get_mut(&4).unwrap().gplb_mut().unwrap()[0].association = Association::Unused;
+        }), vec![
+            error("01TREE04.DAT", 0x48, "Used group 2 is after an unused group"),
+            warning("01TREE04.DAT", 0x4040, "Only 5 of the 9 titles of TPLB belong to a group"),
+            warning("03GINF04.DAT", 0xC8, "Key ab630 of item 2 should be 0"),
+        ]);
+        assert_eq!(violations(|database| {
+            database.cidlst.cilb_mut().unwrap().pop();
+            database.cidlst.relayout();
+        }), vec![
+            error("05CIDLST.DAT", 0x24, "04CNTINF lists 10 titles, but 05CIDLST lists 9 titles"),
+        ]);
+    }
+}
@@ -0,0 +1,248 @@
+use parser::{Class, ClassKind, Frame, Result, Table};
+
+pub mod music;
+
+/// Write a table in the layout read by `parse_table`: each class is written at the address given by its description.
+/// A class which does not fit before the address of the next one (see `Table::relayout`) is an error.
+pub fn write_table(table: &Table) -> Result<Vec<u8>> {
+    let mut writer = Writer::new();
+    writer.table(table)?;
+    Ok(writer.into_bytes())
+}
+
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self {
+            buffer: vec![],
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn class(&mut self, class: &Class) {
+        self.bytes(&class.name);
+        self.u16(class.element_count);
+        self.u16(class.element_length);
+        self.bytes(&class.header);
+        let element_length = class.element_length as usize;
+        match class.kind {
+            ClassKind::Cilb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.bytes(&element.drmed_oma_key);
+                    self.bytes(&element.trailing);
+                    self.pad_to(start + element_length);
+                }
+            },
+            ClassKind::Cnfb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.bytes(&element.reserved);
+                    self.u16(element.protection);
+                    self.u32(element.file_properties);
+                    self.u32(element.title_key);
+                    self.u16(element.part_count);
+                    self.u16(element.part_size);
+                    self.frames(&element.frames);
+                    self.bytes(&element.trailing);
+                    self.pad_to(start + element_length);
+                }
+            },
+            ClassKind::Gpfb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.bytes(&element.magic_key);
+                    self.u32(element.key);
+                    self.u16(element.part_count);
+                    self.u16(element.part_size);
+                    self.frames(&element.frames);
+                    self.bytes(&element.trailing);
+                    self.pad_to(start + element_length);
+                }
+            },
+            ClassKind::Gplb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.u16(element.id);
+                    self.u16(element.association.value());
+                    self.u16(element.title_id);
+                    self.bytes(&element.reserved);
+                    self.pad_to(start + element_length);
+                }
+            },
+            ClassKind::Gtfb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.bytes(&element.magic_key);
+                    self.u32(element.global_key);
+                    self.u16(element.part_count);
+                    self.u16(element.part_size);
+                    self.frames(&element.frames);
+                    self.bytes(&element.trailing);
+                    self.pad_to(start + element_length);
+                }
+            },
+            ClassKind::Gtlb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.u16(element.file_ref);
+                    self.u16(element.unknown1);
+                    self.bytes(&element.reserved1);
+                    self.u16(element.tags.len() as u16);
+                    self.bytes(&element.reserved2);
+                    for tag in &element.tags {
+                        self.bytes(tag);
+                    }
+                    self.bytes(&element.trailing);
+                    self.pad_to(start + element_length);
+                }
+            },
+            ClassKind::Sysb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.bytes(&element.data);
+                    self.pad_to(start + element_length);
+                }
+            },
+            ClassKind::Tplb(ref elements) => {
+                for element in elements {
+                    let start = self.len();
+                    self.u16(element.title_id);
+                    self.pad_to(start + element_length);
+                }
+            },
+        }
+    }
+
+    fn frames(&mut self, frames: &[Frame]) {
+        for frame in frames {
+            self.bytes(&frame.tag);
+            self.u16(frame.encoding);
+            self.bytes(&frame.data);
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn pad_to(&mut self, len: usize) {
+        if len > self.buffer.len() {
+            self.buffer.resize(len, 0);
+        }
+    }
+
+    /// Pad up to the address, which must not be already written.
+    fn seek(&mut self, address: usize, name: &[u8]) -> Result<()> {
+        if self.buffer.len() > address {
+            return Err(format!("{}: the content ends at 0x{:X}, after 0x{:X}", String::from_utf8_lossy(name),
+                self.buffer.len(), address));
+        }
+        self.pad_to(address);
+        Ok(())
+    }
+
+    fn table(&mut self, table: &Table) -> Result<()> {
+        self.bytes(&table.name);
+        self.u32(0x01010000);
+        self.u8(table.class_count);
+        self.bytes(&table.reserved);
+        self.pad_to(16);
+        for class_description in &table.class_descriptions {
+            self.bytes(&class_description.name);
+            self.u32(class_description.address);
+            self.u32(class_description.len);
+            self.bytes(&class_description.reserved);
+        }
+        for (class, class_description) in table.classes.iter().zip(&table.class_descriptions) {
+            let address = class_description.address as usize;
+            self.seek(address, &class.name)?;
+            self.class(class);
+            self.bytes(&class.padding);
+            self.seek(address + class_description.len as usize, &class.name)?;
+        }
+        self.bytes(&table.trailing);
+        Ok(())
+    }
+
+    fn u16(&mut self, num: u16) {
+        self.bytes(&[(num >> 8) as u8, num as u8]);
+    }
+
+    fn u32(&mut self, num: u32) {
+        self.bytes(&[(num >> 24) as u8, (num >> 16) as u8, (num >> 8) as u8, num as u8]);
+    }
+
+    fn u8(&mut self, num: u8) {
+        self.buffer.push(num);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use parser::{ClassKind, parse_table};
+    use super::write_table;
+
+    fn round_trip(bytes: &[u8], kinds: &[&str]) {
+        let table = parse_table(bytes).unwrap();
+        let names: Vec<_> = table.classes.iter()
+            .map(|class| match class.kind {
+                ClassKind::Cilb(_) => "CILB",
+                ClassKind::Cnfb(_) => "CNFB",
+                ClassKind::Gpfb(_) => "GPFB",
+                ClassKind::Gplb(_) => "GPLB",
+                ClassKind::Gtfb(_) => "GTFB",
+                ClassKind::Gtlb(_) => "GTLB",
+                ClassKind::Sysb(_) => "SYSB",
+                ClassKind::Tplb(_) => "TPLB",
+            })
+            .collect();
+        assert_eq!(names, kinds);
+        assert!(write_table(&table).unwrap() == bytes);
+    }
+
+    /// Every table of the fixtures, with the classes expected from the prefix of its name.
+    #[test]
+    fn write_parsed_tables() {
+        let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/OMGAUDIO");
+        let mut count = 0;
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let kinds: &[&str] =
+                match &name[..2] {
+                    "00" => &["SYSB", "GTLB"],
+                    "01" => &["GPLB", "TPLB"],
+                    "02" => &["GTFB"],
+                    "03" => &["GPFB"],
+                    "04" => &["CNFB"],
+                    "05" => &["CILB"],
+                    _ => panic!("Unexpected fixture {}", name),
+                };
+            round_trip(&fs::read(&path).unwrap(), kinds);
+            count += 1;
+        }
+        assert_eq!(count, 16);
+    }
+
+    #[test]
+    fn overflowing_class() {
+        let mut table = parse_table(include_bytes!("../../tests/fixtures/OMGAUDIO/01TREE01.DAT")).unwrap();
+        let tplb_address = table.class_descriptions[1].address;
+        table.class_descriptions[0].len = tplb_address;
+        table.class_descriptions[1].address = 0x20;
+        assert!(write_table(&table).is_err());
+    }
+}
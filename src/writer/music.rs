@@ -0,0 +1,147 @@
+use parser::Result;
+use parser::music::{EA3_BLOCK_OFFSET, HEADER_SIZE, MP3_FORMAT, NO_PROTECTION, Ea3Block, Tag};
+use parser::text;
+use super::Writer;
+
+/// Build an OMA file from an MP3 file: its ID3 tags are removed and replaced by the OMA header.
+pub fn mp3_to_oma(mp3: &[u8], tag: &Tag) -> Result<Vec<u8>> {
+    let block = Ea3Block {
+        version: 2,
+        protection: NO_PROTECTION,
+        format: MP3_FORMAT,
+    };
+    let mut oma = write_header(tag, &block)?;
+    oma.extend_from_slice(strip_id3(mp3));
+    Ok(oma)
+}
+
+/// Remove the ID3v2 tag at the beginning and the ID3v1 tag at the end of an MP3 file.
+pub fn strip_id3(mp3: &[u8]) -> &[u8] {
+    let mut audio = mp3;
+    if audio.len() >= 10 && &audio[..3] == b"ID3" {
+        let size = syncsafe(&audio[6..10]) as usize;
+        let footer_size = if audio[5] & 0x10 != 0 { 10 } else { 0 };
+        audio = &audio[(10 + size + footer_size).min(audio.len())..];
+    }
+    if audio.len() >= 128 && &audio[audio.len() - 128..audio.len() - 125] == b"TAG" {
+        audio = &audio[..audio.len() - 128];
+    }
+    audio
+}
+
+/// Write the 0x0C60 bytes of the OMA header.
+pub fn write_header(tag: &Tag, block: &Ea3Block) -> Result<Vec<u8>> {
+    let mut frames = Writer::new();
+    let text_frames = [(b"TIT2", &tag.title), (b"TPE1", &tag.artist), (b"TALB", &tag.album), (b"TCON", &tag.genre),
+        (b"TSOP", &tag.sort_name)];
+    for &(id, value) in &text_frames {
+        if let Some(ref value) = *value {
+            text_frame(&mut frames, id, value);
+        }
+    }
+    if let Some(track_number) = tag.track_number {
+        user_text_frame(&mut frames, "OMG_TRACK", &track_number.to_string());
+    }
+    if let Some(year) = tag.year {
+        text_frame(&mut frames, b"TYER", &format!("{:04}", year));
+        user_text_frame(&mut frames, "OMG_TRLDA", &format!("{:04}/01/01 00:00:00", year));
+    }
+    if let Some(length) = tag.length {
+        text_frame(&mut frames, b"TLEN", &length.to_string());
+    }
+    let tag_size = EA3_BLOCK_OFFSET - 10;
+    if frames.len() > tag_size {
+        return Err(format!("The tag frames take {} bytes, but there are only {} bytes available", frames.len(), tag_size));
+    }
+
+    let mut writer = Writer::new();
+    writer.bytes(b"ea3");
+    writer.u16(0x0300);
+    writer.u8(0);
+    writer.syncsafe_u32(tag_size as u32);
+    writer.bytes(&frames.into_bytes());
+    writer.pad_to(EA3_BLOCK_OFFSET);
+
+    writer.bytes(b"EA3");
+    writer.u8(block.version);
+    writer.u16((HEADER_SIZE - EA3_BLOCK_OFFSET) as u16);
+    writer.u16(block.protection);
+    // Constant found in the files written by SonicStage.
+    writer.bytes(&[0, 0, 0, 0, 0x01, 0x0F, 0x50, 0x00]);
+    writer.pad_to(EA3_BLOCK_OFFSET + 0x20);
+    writer.u32(block.format);
+    writer.pad_to(HEADER_SIZE);
+    Ok(writer.into_bytes())
+}
+
+impl Writer {
+    fn syncsafe_u32(&mut self, num: u32) {
+        self.bytes(&[(num >> 21) as u8 & 0x7F, (num >> 14) as u8 & 0x7F, (num >> 7) as u8 & 0x7F, num as u8 & 0x7F]);
+    }
+}
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32 & 0x7F) << 21 | (bytes[1] as u32 & 0x7F) << 14 | (bytes[2] as u32 & 0x7F) << 7 | bytes[3] as u32 & 0x7F
+}
+
+fn frame(writer: &mut Writer, id: &[u8], data: &[u8]) {
+    writer.bytes(id);
+    writer.u32(data.len() as u32);
+    writer.u16(0);
+    writer.bytes(data);
+}
+
+fn text_frame(writer: &mut Writer, id: &[u8], value: &str) {
+    let mut data = vec![text::UTF16_BE as u8];
+    data.extend(text::encode_utf16(value));
+    frame(writer, id, &data);
+}
+
+fn user_text_frame(writer: &mut Writer, description: &str, value: &str) {
+    let mut data = vec![text::UTF16_BE as u8];
+    data.extend(text::encode_utf16(description));
+    data.extend_from_slice(&[0, 0]);
+    data.extend(text::encode_utf16(value));
+    frame(writer, b"TXXX", &data);
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::music::{HEADER_SIZE, MP3_FORMAT, NO_PROTECTION, Tag, parse_header};
+    use super::mp3_to_oma;
+
+    #[test]
+    fn convert_mp3_to_oma() {
+        let audio: Vec<u8> = (0..1000).map(|index| index as u8).collect();
+        // An empty ID3v2.3 tag with 10 bytes of padding, the audio, then an ID3v1 tag.
+        let mut mp3 = b"ID3\x03\x00\x00\x00\x00\x00\x0A".to_vec();
+        mp3.extend_from_slice(&[0; 10]);
+        mp3.extend_from_slice(&audio);
+        mp3.extend_from_slice(b"TAG");
+        mp3.extend_from_slice(&[0; 125]);
+        let tag = Tag {
+            title: Some("Système".to_string()),
+            artist: Some("Les Rita Mitsouko".to_string()),
+            sort_name: Some("Rita Mitsouko".to_string()),
+            track_number: Some(12),
+            year: Some(1986),
+            length: Some(215000),
+            ..Tag::default()
+        };
+
+        let oma = mp3_to_oma(&mp3, &tag).unwrap();
+        assert_eq!(oma.len(), HEADER_SIZE + audio.len());
+        assert!(oma[HEADER_SIZE..] == audio[..]);
+        let header = parse_header(&oma).unwrap();
+        assert_eq!(header.audio_offset, HEADER_SIZE);
+        assert_eq!(header.block.protection, NO_PROTECTION);
+        assert_eq!(header.block.format, MP3_FORMAT);
+        assert_eq!(header.tag.title.as_ref().unwrap(), "Système");
+        assert_eq!(header.tag.artist.as_ref().unwrap(), "Les Rita Mitsouko");
+        assert_eq!(header.tag.sort_name.as_ref().unwrap(), "Rita Mitsouko");
+        assert_eq!(header.tag.album, None);
+        assert_eq!(header.tag.track_number, Some(12));
+        assert_eq!(header.tag.year, Some(1986));
+        assert_eq!(header.tag.length, Some(215000));
+    }
+}